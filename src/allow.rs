@@ -0,0 +1,309 @@
+//! Restriction of a file system to a list of users and groups.
+//!
+//! The mount has to allow other users for anybody but the user
+//! running the file system to reach it, which lets every user on the
+//! host in. The wrapper here narrows that down to the users and groups
+//! that should have access.
+
+use fuser::{
+    Filesystem, KernelConfig, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
+    ReplyEntry, ReplyIoctl, ReplyOpen, ReplyWrite, ReplyXattr, Request, TimeOrNow,
+};
+use libc::{c_int, EACCES};
+use log::debug;
+use std::ffi::OsStr;
+use std::time::SystemTime;
+
+/**
+ * File system that only passes on operations from allowed users and
+ * replies with `EACCES` to everybody else.
+ *
+ * A request is allowed if it comes from one of the allowed users, from
+ * a user whose group is one of the allowed groups, or from the user
+ * running the file system. FUSE only passes on the primary group of
+ * the caller, so supplementary groups are not considered. If no users
+ * or groups are given, all requests are allowed.
+ *
+ * Operations flushing and releasing handles are always passed on so
+ * that handles are not leaked. As for `Recorder`, only the operations
+ * implemented by the file systems in this repository are passed on.
+ */
+pub struct AllowList<FS: Filesystem> {
+    inner: FS,
+    uids: Vec<u32>,
+    gids: Vec<u32>,
+    owner: u32,
+}
+
+impl<FS: Filesystem> AllowList<FS> {
+    pub fn new(inner: FS, uids: Vec<u32>, gids: Vec<u32>) -> AllowList<FS> {
+        AllowList {
+            inner,
+            uids,
+            gids,
+            owner: unsafe { libc::getuid() },
+        }
+    }
+
+    fn allowed(&self, req: &Request) -> bool {
+        let allowed = (self.uids.is_empty() && self.gids.is_empty())
+            || req.uid() == self.owner
+            || self.uids.contains(&req.uid())
+            || self.gids.contains(&req.gid());
+        if !allowed {
+            debug!(
+                "denied request req={} from uid={} gid={} pid={}",
+                req.unique(),
+                req.uid(),
+                req.gid(),
+                req.pid()
+            );
+        }
+        allowed
+    }
+}
+
+impl<FS: Filesystem> Filesystem for AllowList<FS> {
+    fn init(&mut self, req: &Request, config: &mut KernelConfig) -> Result<(), c_int> {
+        self.inner.init(req, config)
+    }
+
+    fn destroy(&mut self) {
+        self.inner.destroy()
+    }
+
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        if !self.allowed(req) {
+            reply.error(EACCES);
+            return;
+        }
+        self.inner.lookup(req, parent, name, reply)
+    }
+
+    fn forget(&mut self, req: &Request, ino: u64, nlookup: u64) {
+        self.inner.forget(req, ino, nlookup)
+    }
+
+    fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
+        if !self.allowed(req) {
+            reply.error(EACCES);
+            return;
+        }
+        self.inner.getattr(req, ino, reply)
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        crtime: Option<SystemTime>,
+        chgtime: Option<SystemTime>,
+        bkuptime: Option<SystemTime>,
+        flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        if !self.allowed(req) {
+            reply.error(EACCES);
+            return;
+        }
+        self.inner.setattr(
+            req, ino, mode, uid, gid, size, atime, mtime, ctime, fh, crtime, chgtime, bkuptime,
+            flags, reply,
+        )
+    }
+
+    fn getxattr(&mut self, req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        if !self.allowed(req) {
+            reply.error(EACCES);
+            return;
+        }
+        self.inner.getxattr(req, ino, name, size, reply)
+    }
+
+    fn listxattr(&mut self, req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        if !self.allowed(req) {
+            reply.error(EACCES);
+            return;
+        }
+        self.inner.listxattr(req, ino, size, reply)
+    }
+
+    fn readlink(&mut self, req: &Request, ino: u64, reply: ReplyData) {
+        if !self.allowed(req) {
+            reply.error(EACCES);
+            return;
+        }
+        self.inner.readlink(req, ino, reply)
+    }
+
+    fn open(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        if !self.allowed(req) {
+            reply.error(EACCES);
+            return;
+        }
+        self.inner.open(req, ino, flags, reply)
+    }
+
+    fn release(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        flags: i32,
+        lock_owner: Option<u64>,
+        flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.inner
+            .release(req, ino, fh, flags, lock_owner, flush, reply)
+    }
+
+    fn flush(&mut self, req: &Request, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        self.inner.flush(req, ino, fh, lock_owner, reply)
+    }
+
+    fn read(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        flags: i32,
+        lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        if !self.allowed(req) {
+            reply.error(EACCES);
+            return;
+        }
+        self.inner
+            .read(req, ino, fh, offset, size, flags, lock_owner, reply)
+    }
+
+    fn opendir(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        if !self.allowed(req) {
+            reply.error(EACCES);
+            return;
+        }
+        self.inner.opendir(req, ino, flags, reply)
+    }
+
+    fn releasedir(&mut self, req: &Request, ino: u64, fh: u64, flags: i32, reply: ReplyEmpty) {
+        self.inner.releasedir(req, ino, fh, flags, reply)
+    }
+
+    fn readdir(&mut self, req: &Request, ino: u64, fh: u64, offset: i64, reply: ReplyDirectory) {
+        if !self.allowed(req) {
+            reply.error(EACCES);
+            return;
+        }
+        self.inner.readdir(req, ino, fh, offset, reply)
+    }
+
+    fn access(&mut self, req: &Request, _ino: u64, _mask: i32, reply: ReplyEmpty) {
+        // The wrapped file systems do not check permissions, so the
+        // only thing to check is whether the user is allowed at all.
+        if self.allowed(req) {
+            reply.ok();
+        } else {
+            reply.error(EACCES);
+        }
+    }
+
+    fn create(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        if !self.allowed(req) {
+            reply.error(EACCES);
+            return;
+        }
+        self.inner
+            .create(req, parent, name, mode, umask, flags, reply)
+    }
+
+    fn write(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        write_flags: u32,
+        flags: i32,
+        lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        if !self.allowed(req) {
+            reply.error(EACCES);
+            return;
+        }
+        self.inner.write(
+            req,
+            ino,
+            fh,
+            offset,
+            data,
+            write_flags,
+            flags,
+            lock_owner,
+            reply,
+        )
+    }
+
+    fn ioctl(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+        out_size: u32,
+        reply: ReplyIoctl,
+    ) {
+        if !self.allowed(req) {
+            reply.error(EACCES);
+            return;
+        }
+        self.inner
+            .ioctl(req, ino, fh, flags, cmd, in_data, out_size, reply)
+    }
+
+    fn copy_file_range(
+        &mut self,
+        req: &Request,
+        ino_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+        reply: ReplyWrite,
+    ) {
+        if !self.allowed(req) {
+            reply.error(EACCES);
+            return;
+        }
+        self.inner.copy_file_range(
+            req, ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags, reply,
+        )
+    }
+}
@@ -1,27 +1,165 @@
 use clap::{crate_version, Arg, Command};
 use daemonize::Daemonize;
+use fuser::consts::FOPEN_DIRECT_IO;
+use fuser::consts::{FUSE_BIG_WRITES, FUSE_EXPORT_SUPPORT};
 use fuser::TimeOrNow;
 use fuser::{
-    FileAttr, FileType, Filesystem, KernelConfig, MountOption, ReplyAttr, ReplyCreate,
-    ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyWrite, Request, FUSE_ROOT_ID,
+    FileAttr, FileType, Filesystem, KernelConfig, MountOption, ReplyAttr, ReplyCreate, ReplyData,
+    ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyIoctl, ReplyOpen, ReplyWrite, ReplyXattr, Request,
+    FUSE_ROOT_ID,
 };
 use libc::{c_int, ENOENT};
-use log::{debug, error, LevelFilter};
-use postgres::Statement;
-use postgres::{Client, NoTls};
+use log::{debug, error, info, warn, LevelFilter};
+use postgres::error::SqlState;
+use postgres::types::ToSql;
+use postgres::{Client, Config, GenericClient, NoTls};
+use postgres::{Row, Statement};
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
 use std::ffi::OsStr;
-use std::fs::File;
-use std::io::ErrorKind;
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
 use std::str::{from_utf8, Utf8Error};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
 use std::time::SystemTime;
-use std::time::{Duration, UNIX_EPOCH};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+mod allow;
+mod import;
+mod lines;
+mod logfile;
+mod recorder;
+
+use allow::AllowList;
+use logfile::RotatingFile;
+use recorder::Recorder;
 
 const MAX_NAME_LENGTH: u32 = 255;
-const BLOCK_SIZE: u64 = 512;
+
+/// Default preferred I/O size reported in `st_blksize`.
+const DEFAULT_IO_BLOCK_SIZE: &str = "512";
+
+/// FUSE protocol version used by fuser given the `abi-7-*` features
+/// enabled. The kernel will use the lower of this and the version it
+/// supports itself.
+const FUSE_PROTOCOL_VERSION: &str = "7.28";
+
+/// Default maximum size of a write request. The kernel default is
+/// 128 KiB, but a larger size means that big writes arrive in fewer
+/// calls.
+const DEFAULT_MAX_WRITE: &str = "1048576";
+
+/// Default size at which the log file is rotated.
+const DEFAULT_LOG_FILE_SIZE: &str = "10485760";
+
+/// Default number of rotated log files to keep.
+const DEFAULT_LOG_FILE_COUNT: &str = "5";
 const ZERO: Duration = Duration::new(0, 0);
 
+/// Lowest inode allocated for files. Inodes below this are reserved
+/// for the root directory and the virtual files.
+const MIN_INODE_START: i32 = 10;
+
+/// Names and inodes of the virtual files. Inodes below
+/// `MIN_INODE_START` are never allocated for files, so these can be
+/// used for virtual files.
+const STATUS_NAME: &str = ".status";
+const STATUS_INODE: u64 = 2;
+const HANDLES_NAME: &str = ".handles";
+const HANDLES_INODE: u64 = 3;
+const ERRORS_NAME: &str = ".errors";
+const ERRORS_INODE: u64 = 6;
+
+/// Name and inode of the directory with the records of each file when
+/// using the record-files layout.
+const RECORDS_NAME: &str = ".records";
+const RECORDS_INODE: u64 = 4;
+
+/// Name and inode of the symbolic link to the most recently created
+/// file.
+const LATEST_NAME: &str = "latest";
+const LATEST_INODE: u64 = 5;
+
+/// Flags for the inodes of the record-files layout. The directory for
+/// the records of a file is the inode of the file with `STREAM_FLAG`
+/// set, and the file for a record is the sequence number of the record
+/// with `RECORD_FLAG` set.
+const STREAM_FLAG: u64 = 1 << 62;
+const RECORD_FLAG: u64 = 1 << 63;
+
+/// Mode bits that are not stored. Files are never executed from the
+/// mount, which is mounted `noexec`, so setuid and setgid would only
+/// mislead, and the sticky bit means nothing for regular files.
+const SPECIAL_MODE_BITS: u32 = libc::S_ISUID | libc::S_ISGID | libc::S_ISVTX;
+
+/// Clear the mode bits that are not stored.
+fn clear_special_bits(mode: u32) -> u32 {
+    if mode & SPECIAL_MODE_BITS != 0 {
+        debug!("clearing mode bits {:#o}", mode & SPECIAL_MODE_BITS);
+    }
+    mode & !SPECIAL_MODE_BITS
+}
+
+/// Name of the file receiving all writes in stream mode.
+const STREAM_FILE: &str = "stream";
+
+/// Name and inode of the directory with statistics for each file. The
+/// statistics file of a file is the inode of the file with
+/// `STATS_FLAG` set.
+const STATS_NAME: &str = ".stats";
+const STATS_INODE: u64 = 7;
+const STATS_FLAG: u64 = 1 << 61;
+
+/// Names of the virtual entries of the root directory. Looking up these
+/// names never finds a file, so files cannot be created with them.
+const RESERVED_NAMES: &[&str] = &[
+    STATUS_NAME,
+    HANDLES_NAME,
+    ERRORS_NAME,
+    RECORDS_NAME,
+    LATEST_NAME,
+    STATS_NAME,
+];
+
+/// File with the inode in the `.stats` directory is the statistics
+/// for, if it is a statistics file.
+fn stats_file(ino: u64) -> Option<i32> {
+    if ino & (STATS_FLAG | STREAM_FLAG | RECORD_FLAG) == STATS_FLAG {
+        Some((ino & !STATS_FLAG) as i32)
+    } else {
+        None
+    }
+}
+
+/// Name of the extended attribute with the number of lines in a file.
+const LINE_COUNT_XATTR: &str = "user.line_count";
+
+/// Reply with the value of an extended attribute. The kernel first
+/// asks with a zero size to find out how large the value is.
+fn reply_xattr(value: &[u8], size: u32, reply: ReplyXattr) {
+    if size == 0 {
+        reply.size(value.len() as u32);
+    } else if value.len() > size as usize {
+        reply.error(libc::ERANGE);
+    } else {
+        reply.data(value);
+    }
+}
+
+/// Ioctl command to wait until all data written to the file system has
+/// been committed to the database. This is `_IO('D', 1)`.
+const COMMIT_IOCTL: u32 = 0x4401;
+
+/// Attributes of directories that are not stored in the database,
+/// which are the directories of the record-files layout.
 const CAPTURE_DIR_ATTR: FileAttr = FileAttr {
-    ino: 1,
+    ino: FUSE_ROOT_ID,
     size: 0,
     blocks: 0,
     atime: UNIX_EPOCH, // 1970-01-01 00:00:00
@@ -39,8 +177,14 @@ const CAPTURE_DIR_ATTR: FileAttr = FileAttr {
 };
 
 fn main() {
-    let matches = Command::new("Database FUSE")
+    let long_version = format!(
+        "{} (FUSE protocol {})",
+        crate_version!(),
+        FUSE_PROTOCOL_VERSION
+    );
+    let mut command = Command::new("Database FUSE")
         .version(crate_version!())
+        .long_version(long_version.as_str())
         .author("Mats Kindahl")
         .arg(
             Arg::new("mount")
@@ -51,27 +195,530 @@ fn main() {
         .arg(
             Arg::new("daemonize")
                 .short('d')
+                .long("daemon")
                 .required(false)
                 .takes_value(false)
+                .conflicts_with("foreground")
                 .help("Daemonize the FUSE process"),
         )
+        .arg(
+            Arg::new("idle-timeout")
+                .long("idle-timeout")
+                .value_name("SECONDS")
+                .default_value("0")
+                .validator(|s| s.parse::<u64>())
+                .help("Unmount after this many seconds without operations on any mount, 0 to never unmount")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("foreground")
+                .long("foreground")
+                .required(false)
+                .takes_value(false)
+                .help("Run the FUSE process in the foreground (default)"),
+        )
         .arg(
             Arg::new("params")
                 .value_name("PARAMS")
                 .help("Database connection parameters")
                 .takes_value(true),
         )
+        .arg(
+            Arg::new("read-params")
+                .long("read-params")
+                .value_name("PARAMS")
+                .help("Connection parameters for a read replica to use for lookups and reads")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("seed")
+                .long("seed")
+                .value_name("FILES:LINES")
+                .validator(parse_seed)
+                .help("Add FILES files of LINES lines each to the database instead of mounting")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("async-writes")
+                .long("async-writes")
+                .takes_value(false)
+                .help("Queue writes and insert them into the database in the background"),
+        )
+        .arg(
+            Arg::new("write-policy")
+                .long("write-policy")
+                .value_name("POLICY")
+                .possible_values(["immediate", "on-close"])
+                .default_value("immediate")
+                .conflicts_with("async-writes")
+                .help("Insert each write when it is made, or all writes to a handle when it is closed")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("sync-writes")
+                .long("sync-writes")
+                .takes_value(false)
+                .conflicts_with("async-writes")
+                .help("Commit each write durably before returning from it (slower)"),
+        )
+        .arg(
+            Arg::new("debug-errors")
+                .long("debug-errors")
+                .value_name("COUNT")
+                .validator(|s| match s.parse::<usize>() {
+                    Ok(0) => Err("must be positive".to_string()),
+                    Ok(_) => Ok(()),
+                    Err(err) => Err(err.to_string()),
+                })
+                .help("Keep the last COUNT database errors in the .errors file of the mount")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("degrade-to-readonly")
+                .long("degrade-to-readonly")
+                .takes_value(false)
+                .conflicts_with("async-writes")
+                .help("Reject writes with EROFS while the database is read-only or full instead of failing each write"),
+        )
+        .arg(
+            Arg::new("queue-size")
+                .long("queue-size")
+                .value_name("WRITES")
+                .default_value("1024")
+                .validator(|s| s.parse::<usize>())
+                .help("Number of writes that can be queued when using asynchronous writes")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("commit-interval")
+                .long("commit-interval")
+                .alias("commit-interval-ms")
+                .value_name("MILLISECONDS")
+                .requires("async-writes")
+                .validator(|s| s.parse::<u64>())
+                .help("Commit queued writes in batches collected over this interval")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("queue-full-policy")
+                .long("queue-full-policy")
+                .value_name("POLICY")
+                .possible_values(["block", "eagain"])
+                .default_value("block")
+                .help("Whether a write to a full queue should block or fail with EAGAIN")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("max-line-bytes")
+                .long("max-line-bytes")
+                .value_name("BYTES")
+                .default_value("1048576")
+                .validator(|s| s.parse::<usize>())
+                .help("Maximum length of a line, longer lines are rejected with EFBIG")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("max-file-size")
+                .long("max-file-size")
+                .value_name("BYTES")
+                .validator(|s| s.parse::<u64>())
+                .help("Maximum size of a file, writes beyond it are rejected with EFBIG")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("delimiter")
+                .long("delimiter")
+                .value_name("BYTE")
+                .default_value("\\n")
+                .validator(parse_delimiter)
+                .help("Byte separating records, either a character or an escape like \\0 or \\x1e")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("keep-empty-lines")
+                .long("keep-empty-lines")
+                .help("Store empty lines instead of skipping them"),
+        )
+        .arg(
+            Arg::new("record-lines")
+                .long("record-lines")
+                .value_name("LINES")
+                .default_value("1")
+                .validator(|s| match s.parse::<usize>() {
+                    Ok(0) => Err("must be positive".to_string()),
+                    Ok(_) => Ok(()),
+                    Err(err) => Err(err.to_string()),
+                })
+                .help("Number of consecutive lines of a write to store as one record")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("max-write")
+                .long("max-write")
+                .value_name("BYTES")
+                .default_value(DEFAULT_MAX_WRITE)
+                .validator(|s| s.parse::<u32>())
+                .help("Maximum size of a single write request from the kernel")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("max-readahead")
+                .long("max-readahead")
+                .value_name("BYTES")
+                .validator(|s| match s.parse::<u32>() {
+                    Ok(0) => Err("must be positive".to_string()),
+                    Ok(_) => Ok(()),
+                    Err(err) => Err(err.to_string()),
+                })
+                .help("Maximum readahead for sequential reads, the kernel default if not given")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("inode-start")
+                .long("inode-start")
+                .value_name("INODE")
+                .default_value("10")
+                .validator(|s| match s.parse::<i32>() {
+                    Ok(ino) if ino >= MIN_INODE_START => Ok(()),
+                    Ok(_) => Err(format!("must be at least {}", MIN_INODE_START)),
+                    Err(err) => Err(err.to_string()),
+                })
+                .help("First inode to allocate for files in new tables")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("fillfactor")
+                .long("fillfactor")
+                .value_name("PERCENT")
+                .validator(|s| match s.parse::<u32>() {
+                    Ok(10..=100) => Ok(()),
+                    Ok(_) => Err("must be between 10 and 100".to_string()),
+                    Err(err) => Err(err.to_string()),
+                })
+                .help("Fill the table pages to PERCENT when inserting")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("autovacuum-threshold")
+                .long("autovacuum-threshold")
+                .value_name("ROWS")
+                .validator(|s| s.parse::<u32>())
+                .help("Minimum number of changed rows before the tables are vacuumed")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("autovacuum-scale-factor")
+                .long("autovacuum-scale-factor")
+                .value_name("FRACTION")
+                .validator(|s| match s.parse::<f64>() {
+                    Ok(fraction) if (0.0..=100.0).contains(&fraction) => Ok(()),
+                    Ok(_) => Err("must be between 0 and 100".to_string()),
+                    Err(err) => Err(err.to_string()),
+                })
+                .help("Fraction of the tables to change, in addition to the threshold, before they are vacuumed")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("partitions")
+                .long("partitions")
+                .value_name("COUNT")
+                .validator(|s| match s.parse::<u32>() {
+                    Ok(0) => Err("must be positive".to_string()),
+                    Ok(_) => Ok(()),
+                    Err(err) => Err(err.to_string()),
+                })
+                .help("Hash partition the tables on the inode into COUNT partitions")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("application-name")
+                .long("application-name")
+                .value_name("NAME")
+                .help("Application name to report to the database [default: db-fuse:MOUNTPOINT]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("init-sql")
+                .long("init-sql")
+                .value_name("FILE")
+                .help("Execute the SQL statements in FILE after connecting to the database")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("unlogged")
+                .long("unlogged")
+                .help("Skip the write-ahead log for the tables, losing all data on a crash"),
+        )
+        .arg(
+            Arg::new("layout")
+                .long("layout")
+                .value_name("LAYOUT")
+                .possible_values(["files", "record-files"])
+                .default_value("files")
+                .help("Also expose each record as a file under .records when using record-files")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("idempotent-writes")
+                .long("idempotent-writes")
+                .help("Ignore lines already stored at the same file position"),
+        )
+        .arg(
+            Arg::new("dedup")
+                .long("dedup")
+                .help("Store each distinct line once in a dictionary table and refer to it from the content"),
+        )
+        .arg(
+            Arg::new("annotate-writer")
+                .long("annotate-writer")
+                .help("Record the user and process writing each line"),
+        )
+        .arg(
+            Arg::new("io-block-size")
+                .long("io-block-size")
+                .value_name("BYTES")
+                .default_value(DEFAULT_IO_BLOCK_SIZE)
+                .validator(|s| match s.parse::<u32>() {
+                    Ok(0) => Err("must be positive".to_string()),
+                    Ok(_) => Ok(()),
+                    Err(err) => Err(err.to_string()),
+                })
+                .help("Preferred I/O size reported to clients in st_blksize")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("max-open-files")
+                .long("max-open-files")
+                .value_name("FILES")
+                .validator(|s| s.parse::<usize>())
+                .help("Maximum number of open files, further opens fail with ENFILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("default-file-mode")
+                .long("default-file-mode")
+                .value_name("MODE")
+                .default_value("644")
+                .validator(|s| match u32::from_str_radix(s, 8) {
+                    Ok(mode) if mode <= 0o777 => Ok(()),
+                    Ok(mode) if mode <= 0o7777 => {
+                        Err("setuid, setgid, and sticky bits are not supported".to_string())
+                    }
+                    Ok(_) => Err("not a permission mode".to_string()),
+                    Err(err) => Err(err.to_string()),
+                })
+                .help("Permissions, in octal, for files created without any permissions")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("atime")
+                .long("atime")
+                .value_name("POLICY")
+                .possible_values(["noatime", "relatime", "strictatime"])
+                .default_value("relatime")
+                .help("When to update the access time of files on reads")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("subtype")
+                .long("subtype")
+                .value_name("NAME")
+                .default_value("dbfuse")
+                .validator(|s| {
+                    if s.is_empty() || s.contains(|c: char| c == ',' || c.is_whitespace()) {
+                        Err("must be a non-empty name without commas or whitespace")
+                    } else {
+                        Ok(())
+                    }
+                })
+                .help("File system subtype, shown as fuse.NAME in /proc/mounts")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("default-permissions")
+                .long("default-permissions")
+                .takes_value(false)
+                .help("Let the kernel check access to files against their owner and permissions"),
+        )
+        .arg(
+            Arg::new("export-support")
+                .long("export-support")
+                .takes_value(false)
+                .help("Tell the kernel that the file system can be exported over NFS"),
+        )
+        .arg(
+            Arg::new("tablespace")
+                .long("tablespace")
+                .value_name("NAME")
+                .help("Tablespace to create the tables and their indexes in")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("table-owner")
+                .long("table-owner")
+                .value_name("ROLE")
+                .help("Role that should own the tables")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("grant-read")
+                .long("grant-read")
+                .value_name("ROLE")
+                .multiple_occurrences(true)
+                .help("Grant ROLE read access to the captured data, can be repeated")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("stream-mode")
+                .long("stream-mode")
+                .help("Present the mount as a single file named stream that all writes are appended to"),
+        )
+        .arg(
+            Arg::new("show-stats")
+                .long("show-stats")
+                .help("List the .stats directory with statistics for each file in the root directory"),
+        )
+        .arg(
+            Arg::new("create-views")
+                .long("create-views")
+                .help("Create a files view with the contents of each file for use from SQL"),
+        )
+        .arg(
+            Arg::new("dump-schema")
+                .long("dump-schema")
+                .help("Print the statements creating the schema with the given options and exit"),
+        )
+        .arg(
+            Arg::new("export")
+                .long("export")
+                .value_name("DIR")
+                .help("Write the contents of all files to DIR and exit")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("vacuum")
+                .long("vacuum")
+                .conflicts_with("export")
+                .help("Vacuum and analyze the tables, print the space reclaimed, and exit"),
+        )
+        .arg(
+            Arg::new("drop-schema")
+                .long("drop-schema")
+                .conflicts_with_all(&["export", "vacuum"])
+                .help("Drop the tables and views of the file system, after asking for confirmation, and exit"),
+        )
+        .arg(
+            Arg::new("force")
+                .long("force")
+                .requires("drop-schema")
+                .help("Drop the schema without asking for confirmation"),
+        )
+        .arg(
+            Arg::new("since")
+                .long("since")
+                .value_name("TIMESTAMP")
+                .requires("export")
+                .help("Only export files modified after TIMESTAMP")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("mounts")
+                .long("mount")
+                .value_name("PATH=PARAMS")
+                .validator(parse_mount)
+                .multiple_occurrences(true)
+                .conflicts_with_all(&["mount", "params"])
+                .help("Mount a file system at PATH using the database at PARAMS, can be repeated with different databases")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("record")
+                .long("record")
+                .value_name("FILE")
+                .conflicts_with("mounts")
+                .help("Log all operations to FILE, one JSON object per line")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("allow-uid")
+                .long("allow-uid")
+                .value_name("UID")
+                .validator(|s| s.parse::<u32>())
+                .multiple_occurrences(true)
+                .help("Only allow access for user UID and the user running the file system, can be repeated")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("allow-gid")
+                .long("allow-gid")
+                .value_name("GID")
+                .validator(|s| s.parse::<u32>())
+                .multiple_occurrences(true)
+                .help("Only allow access for users in group GID and the user running the file system, can be repeated")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("replay")
+                .long("replay")
+                .value_name("FILE")
+                .conflicts_with_all(&["mounts", "record"])
+                .help("Apply the operations logged in FILE to the database instead of mounting")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("import")
+                .long("import")
+                .value_name("DIR")
+                .conflicts_with_all(&["mounts", "record", "replay", "stream-mode"])
+                .help("Import the files in DIR into the database instead of mounting")
+                .takes_value(true),
+        )
         .arg(
             Arg::new("v")
                 .short('v')
                 .multiple_occurrences(true)
                 .help("Sets the level of verbosity"),
         )
-        .get_matches();
+        .arg(
+            Arg::new("quiet")
+                .short('q')
+                .long("quiet")
+                .conflicts_with("v")
+                .help("Do not log anything, not even errors"),
+        )
+        .arg(
+            Arg::new("log-file")
+                .long("log-file")
+                .value_name("FILE")
+                .help("Write the log to FILE instead of stderr, rotating it by size")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("log-file-size")
+                .long("log-file-size")
+                .value_name("BYTES")
+                .default_value(DEFAULT_LOG_FILE_SIZE)
+                .requires("log-file")
+                .validator(|s| s.parse::<u64>())
+                .help("Size at which the log file is rotated")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("log-file-count")
+                .long("log-file-count")
+                .value_name("COUNT")
+                .default_value(DEFAULT_LOG_FILE_COUNT)
+                .requires("log-file")
+                .validator(|s| s.parse::<usize>())
+                .help("Number of rotated log files to keep")
+                .takes_value(true),
+        );
+    let matches = command.get_matches_mut();
 
     // Set up logger
     let verbosity: u64 = matches.occurrences_of("v");
     let log_level = match verbosity {
+        _ if matches.is_present("quiet") => LevelFilter::Off,
         0 => LevelFilter::Error,
         1 => LevelFilter::Warn,
         2 => LevelFilter::Info,
@@ -79,26 +726,200 @@ fn main() {
         _ => LevelFilter::Trace,
     };
 
-    env_logger::builder()
-        .format_timestamp_nanos()
-        .filter_level(log_level)
-        .init();
+    let mut builder = env_logger::builder();
+    builder.format_timestamp_nanos().filter_level(log_level);
+    // The file is opened before daemonizing, and the descriptor is
+    // inherited by the daemon, so the log does not end up in the
+    // daemon's stderr file.
+    if let Some(path) = matches.value_of("log-file") {
+        let max_size: u64 = matches.value_of_t_or_exit("log-file-size");
+        let count: usize = matches.value_of_t_or_exit("log-file-count");
+        match RotatingFile::open(path, max_size, count) {
+            Ok(file) => {
+                builder.target(env_logger::Target::Pipe(Box::new(file)));
+            }
+            Err(err) => {
+                eprintln!("cannot open log file {}: {}", path, err);
+                std::process::exit(1);
+            }
+        }
+    }
+    builder.init();
 
     debug!("Setting up logger");
     let mountpoint: String = matches.value_of("mount").unwrap_or_default().to_string();
     debug!("Mountpoint is {}", mountpoint);
 
-    let options = vec![
+    // With relatime, the access time is only updated when it is older
+    // than the modification time, which avoids an update for every
+    // read while still letting tools see that a file was read after
+    // being modified.
+    let atime = match matches.value_of("atime") {
+        Some("noatime") => MountOption::NoAtime,
+        Some("strictatime") => MountOption::CUSTOM("strictatime".to_string()),
+        _ => MountOption::CUSTOM("relatime".to_string()),
+    };
+    let mut options = vec![
         MountOption::AllowOther,
         MountOption::RW,
         MountOption::NoExec,
+        atime,
         MountOption::FSName("hello".to_string()),
+        MountOption::Subtype(matches.value_of("subtype").unwrap().to_string()),
     ];
+    if matches.is_present("default-permissions") {
+        options.push(MountOption::DefaultPermissions);
+    }
+
+    let init_sql = matches
+        .value_of("init-sql")
+        .map(|path| match fs::read_to_string(path) {
+            Ok(sql) => sql,
+            Err(err) => {
+                error!("reading {} failed: {}", path, err);
+                std::process::exit(1);
+            }
+        });
+
+    let fs_options = Options {
+        write_queue_size: if matches.is_present("async-writes") {
+            Some(matches.value_of_t("queue-size").unwrap())
+        } else {
+            None
+        },
+        commit_interval: matches
+            .value_of("commit-interval")
+            .map(|millis| Duration::from_millis(millis.parse().unwrap())),
+        queue_full_policy: match matches.value_of("queue-full-policy") {
+            Some("eagain") => QueueFullPolicy::Fail,
+            _ => QueueFullPolicy::Block,
+        },
+        max_line_bytes: matches.value_of_t("max-line-bytes").unwrap(),
+        max_file_size: matches
+            .value_of("max-file-size")
+            .map(|bytes| bytes.parse().unwrap()),
+        delimiter: parse_delimiter(matches.value_of("delimiter").unwrap()).unwrap(),
+        keep_empty_lines: matches.is_present("keep-empty-lines"),
+        record_lines: matches.value_of_t("record-lines").unwrap(),
+        max_write: matches.value_of_t("max-write").unwrap(),
+        max_readahead: matches
+            .value_of("max-readahead")
+            .map(|bytes| bytes.parse().unwrap()),
+        export_support: matches.is_present("export-support"),
+        inode_start: matches.value_of_t("inode-start").unwrap(),
+        storage_parameters: [
+            ("fillfactor", "fillfactor"),
+            ("autovacuum_vacuum_threshold", "autovacuum-threshold"),
+            ("autovacuum_vacuum_scale_factor", "autovacuum-scale-factor"),
+        ]
+        .iter()
+        .filter_map(|(parameter, arg)| {
+            matches
+                .value_of(arg)
+                .map(|value| format!("{} = {}", parameter, value))
+        })
+        .collect(),
+        unlogged: matches.is_present("unlogged"),
+        partitions: matches
+            .value_of("partitions")
+            .map(|count| count.parse().unwrap()),
+        application_name: matches.value_of("application-name").map(str::to_string),
+        init_sql,
+        layout: match matches.value_of("layout") {
+            Some("record-files") => Layout::RecordFiles,
+            _ => Layout::Files,
+        },
+        show_stats: matches.is_present("show-stats"),
+        stream_mode: matches.is_present("stream-mode"),
+        read_params: matches.value_of("read-params").map(str::to_string),
+        // Reading the stream locates lines by their position, so
+        // stream mode always stores it.
+        idempotent_writes: matches.is_present("idempotent-writes")
+            || matches.is_present("stream-mode"),
+        dedup: matches.is_present("dedup"),
+        annotate_writer: matches.is_present("annotate-writer"),
+        write_policy: match matches.value_of("write-policy") {
+            Some("on-close") => WritePolicy::OnClose,
+            _ => WritePolicy::Immediate,
+        },
+        sync_writes: matches.is_present("sync-writes"),
+        degrade_to_readonly: matches.is_present("degrade-to-readonly"),
+        debug_errors: matches
+            .value_of("debug-errors")
+            .map(|count| count.parse().unwrap()),
+        create_views: matches.is_present("create-views"),
+        tablespace: matches.value_of("tablespace").map(str::to_string),
+        table_owner: matches.value_of("table-owner").map(str::to_string),
+        readers: matches
+            .values_of("grant-read")
+            .map(|roles| roles.map(str::to_string).collect())
+            .unwrap_or_default(),
+        io_block_size: matches.value_of_t("io-block-size").unwrap(),
+        max_open_files: matches
+            .value_of("max-open-files")
+            .map(|files| files.parse().unwrap()),
+        default_file_mode: u32::from_str_radix(matches.value_of("default-file-mode").unwrap(), 8)
+            .unwrap(),
+    };
+
+    if matches.is_present("dump-schema") {
+        for statement in schema_statements(&fs_options) {
+            println!("{};", statement);
+        }
+        return;
+    }
+
+    // Maintenance modes do not mount anything, so accept the
+    // connection parameters without a mount point as well.
+    // Without them there is nothing to connect to, which is reported
+    // as a usage error.
+    let maintenance_params = matches
+        .value_of("params")
+        .or_else(|| matches.value_of("mount"));
+    let mut maintenance_params = || {
+        maintenance_params.unwrap_or_else(|| {
+            command
+                .error(
+                    clap::ErrorKind::MissingRequiredArgument,
+                    "The connection parameters PARAMS are required",
+                )
+                .exit()
+        })
+    };
+
+    if let Some(dir) = matches.value_of("export") {
+        let params = maintenance_params();
+        if let Err(err) = export(
+            params,
+            Path::new(dir),
+            matches.value_of("since"),
+            parse_delimiter(matches.value_of("delimiter").unwrap()).unwrap(),
+        ) {
+            error!("export failed: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if matches.is_present("vacuum") {
+        if let Err(err) = vacuum(maintenance_params()) {
+            error!("vacuum failed: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    let params: String = matches.value_of("params").unwrap().to_string();
-    let filesystem = DatabaseFS::new(params).unwrap();
-    debug!("Database connection established");
+    if matches.is_present("drop-schema") {
+        if let Err(err) = drop_schema(maintenance_params(), matches.is_present("force")) {
+            error!("dropping schema failed: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
 
+    // Daemonize before connecting to the database and starting any
+    // threads since these do not survive the fork. The parent exits
+    // inside start(), so only the daemon continues past this point.
     if matches.is_present("daemonize") {
         let daemonize = Daemonize::new()
             .pid_file("/tmp/db-fuse.pid")
@@ -107,12 +928,97 @@ fn main() {
             .stderr(File::create("/tmp/db-fuse.err").unwrap());
 
         match daemonize.start() {
-            Ok(_) => println!("Success, daemonized"),
-            Err(e) => eprintln!("Error, {}", e),
+            Ok(_) => info!("daemonized"),
+            Err(e) => {
+                error!("failed to daemonize: {}", e);
+                std::process::exit(1);
+            }
+        };
+    }
+
+    let mounts = match matches.values_of("mounts") {
+        Some(specs) => specs.map(|spec| parse_mount(spec).unwrap()).collect(),
+        None => vec![(mountpoint, maintenance_params().to_string())],
+    };
+
+    let mut filesystems = Vec::new();
+    for (mountpoint, params) in mounts {
+        let mut options = fs_options.clone();
+        options
+            .application_name
+            .get_or_insert_with(|| format!("db-fuse:{}", mountpoint));
+        let filesystem = match DatabaseFS::new(params, options) {
+            Ok(filesystem) => filesystem,
+            Err(err) => {
+                error!("setting up file system for {} failed: {}", mountpoint, err);
+                std::process::exit(1);
+            }
         };
+        debug!("Database connection established for {}", mountpoint);
+        filesystems.push((mountpoint, filesystem));
+    }
+
+    if let Some(spec) = matches.value_of("seed") {
+        let (files, lines) = parse_seed(spec).unwrap();
+        let (_, mut filesystem) = filesystems.pop().unwrap();
+        if let Err(err) = filesystem.seed(files, lines) {
+            error!("seeding failed: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(path) = matches.value_of("replay") {
+        let (_, mut filesystem) = filesystems.pop().unwrap();
+        if let Err(err) = filesystem.replay(Path::new(path)) {
+            error!("replay failed: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(dir) = matches.value_of("import") {
+        let (_, mut filesystem) = filesystems.pop().unwrap();
+        if let Err(err) = filesystem.import(Path::new(dir)) {
+            error!("import failed: {}", err);
+            std::process::exit(1);
+        }
+        return;
     }
 
-    let result = fuser::mount2(filesystem, mountpoint, &options);
+    let ids = |name| -> Vec<u32> {
+        matches
+            .values_of(name)
+            .map(|values| values.map(|s| s.parse().unwrap()).collect())
+            .unwrap_or_default()
+    };
+    let (uids, gids) = (ids("allow-uid"), ids("allow-gid"));
+
+    let idle_timeout = Duration::from_secs(matches.value_of_t("idle-timeout").unwrap());
+    let statuses: Vec<_> = filesystems
+        .iter()
+        .map(|(_, filesystem)| filesystem.status.clone())
+        .collect();
+    let idle = move || {
+        !idle_timeout.is_zero()
+            && statuses
+                .iter()
+                .all(|status| status.idle_time() >= idle_timeout)
+    };
+
+    install_signal_handlers();
+    let result = if let Some(path) = matches.value_of("record") {
+        let (mountpoint, filesystem) = filesystems.pop().unwrap();
+        let log = File::create(path).unwrap();
+        let filesystem = AllowList::new(Recorder::new(filesystem, log), uids, gids);
+        mount_all(vec![(mountpoint, filesystem)], &options, idle)
+    } else {
+        let filesystems = filesystems
+            .into_iter()
+            .map(|(mountpoint, fs)| (mountpoint, AllowList::new(fs, uids.clone(), gids.clone())))
+            .collect();
+        mount_all(filesystems, &options, idle)
+    };
     if let Err(e) = result {
         // Return a special error code for permission denied, which usually indicates that
         // "user_allow_other" is missing from /etc/fuse.conf
@@ -123,23 +1029,1455 @@ fn main() {
     }
 }
 
-fn new_attr(ino: i64, uid: u32, gid: u32, mode: u32) -> FileAttr {
-    FileAttr {
-        ino: ino as u64,
-        size: 0,
-        atime: SystemTime::now(),
-        mtime: SystemTime::now(),
-        ctime: SystemTime::now(),
+/// Set when the process receives a signal asking it to terminate.
+static TERMINATE: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_termination(_signal: c_int) {
+    TERMINATE.store(true, Ordering::Relaxed);
+}
+
+/// Handle the signals normally used to stop the process by unmounting
+/// the file systems, so that queued writes are committed before the
+/// process exits.
+fn install_signal_handlers() {
+    for signal in [libc::SIGHUP, libc::SIGINT, libc::SIGTERM] {
+        unsafe {
+            libc::signal(
+                signal,
+                request_termination as *const () as libc::sighandler_t,
+            );
+        }
+    }
+}
+
+/// Mount the file systems, each running in a session of its own.
+///
+/// This returns when any of the file systems is unmounted, the process
+/// is asked to terminate, or `idle` returns true, after unmounting the
+/// remaining ones.
+fn mount_all<FS: Filesystem + Send + 'static>(
+    filesystems: Vec<(String, FS)>,
+    options: &[MountOption],
+    idle: impl Fn() -> bool,
+) -> std::io::Result<()> {
+    let mut sessions = Vec::new();
+    for (mountpoint, filesystem) in filesystems {
+        info!("mounting {}", mountpoint);
+        sessions.push(fuser::spawn_mount2(filesystem, mountpoint, options)?);
+    }
+
+    while !TERMINATE.load(Ordering::Relaxed)
+        && sessions.iter().all(|session| !session.guard.is_finished())
+    {
+        if idle() {
+            info!("unmounting after being idle");
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    // Dropping the rest of a session unmounts the file system, after
+    // which its thread finishes and drops the file system.
+    let guards: Vec<_> = sessions.into_iter().map(|session| session.guard).collect();
+    let mut result = Ok(());
+    for guard in guards {
+        if let Err(err) = guard.join().unwrap() {
+            error!("session failed: {}", err);
+            result = Err(err);
+        }
+    }
+    result
+}
+
+/// Export the contents of the files to a directory, one file for each
+/// file in the file system.
+///
+/// This connects to the database directly rather than creating a
+/// file system, so it can be used to export a file system that is
+/// currently mounted. If `since` is given, only files modified after
+/// that time are exported, which gives an incremental export in the
+/// same format as a full one. Each line is terminated by `delimiter`.
+fn export(
+    params: &str,
+    dir: &Path,
+    since: Option<&str>,
+    delimiter: u8,
+) -> Result<(), Box<dyn Error>> {
+    let mut client = Client::connect(params, NoTls)?;
+    let files = client.query(
+        "SELECT ino, name FROM inodes \
+         WHERE ino <> 1 AND ($1::text IS NULL OR mtime > CAST($1::text AS timestamptz)) ORDER BY ino",
+        &[&since],
+    )?;
+    let content = client.prepare(&format!(
+        "SELECT {} AS line FROM {} WHERE c.ino = $1 ORDER BY c.seq",
+        CONTENT_LINE, CONTENT_JOIN
+    ))?;
+    fs::create_dir_all(dir)?;
+    for file in files {
+        let ino: i32 = file.get("ino");
+        let name: &str = file.get("name");
+        let mut output = File::create(dir.join(name))?;
+        for row in client.query(&content, &[&ino])? {
+            let line: &str = row.get("line");
+            output.write_all(line.as_bytes())?;
+            output.write_all(&[delimiter])?;
+        }
+        debug!("exported {:?}", name);
+    }
+    Ok(())
+}
+
+/// Vacuum and analyze the tables and print the size of each table
+/// before and after.
+///
+/// Like `export`, this connects to the database directly, so it can be
+/// used while the file system is mounted. A plain vacuum makes the
+/// space of removed rows available for new rows but rarely returns it
+/// to the operating system, so the space reclaimed is often zero.
+fn vacuum(params: &str) -> Result<(), Box<dyn Error>> {
+    let mut client = Client::connect(params, NoTls)?;
+    // The size of a partitioned table is the size of its partitions,
+    // while the partition tree of other tables is empty.
+    let size = client.prepare(
+        "SELECT coalesce(sum(pg_total_relation_size(relid)), \
+                         pg_total_relation_size($1::text::regclass))::bigint \
+         FROM pg_partition_tree($1::text::regclass)",
+    )?;
+    let tables = PARTITIONED_TABLES.iter().map(|(table, _)| table);
+    for table in tables.chain(&["dictionary"]) {
+        let before: i64 = client.query_one(&size, &[table])?.get(0);
+        client.batch_execute(&format!("VACUUM (ANALYZE) {}", table))?;
+        let after: i64 = client.query_one(&size, &[table])?.get(0);
+        println!(
+            "{}: {} bytes before, {} bytes after, {} bytes reclaimed",
+            table,
+            before,
+            after,
+            (before - after).max(0)
+        );
+    }
+    Ok(())
+}
+
+/// Drop the tables and views of the file system, asking for
+/// confirmation on the terminal unless `force` is set.
+///
+/// The tables are kept when the file system is unmounted, so this is
+/// the way to remove them. Everything is dropped in one transaction,
+/// so a file system that is mounted blocks the drop until it is
+/// unmounted.
+fn drop_schema(params: &str, force: bool) -> Result<(), Box<dyn Error>> {
+    let mut client = Client::connect(params, NoTls)?;
+    if !force {
+        print!(
+            "Drop all files stored in database {:?}? [y/N] ",
+            client
+                .query_one("SELECT current_database()", &[])?
+                .get::<_, String>(0)
+        );
+        std::io::stdout().flush()?;
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer)?;
+        if !matches!(answer.trim(), "y" | "Y" | "yes") {
+            return Err("not confirmed".into());
+        }
+    }
+    let mut txn = client.transaction()?;
+    for statement in drop_statements() {
+        debug!("executing {:?}", statement);
+        txn.batch_execute(&statement)?;
+    }
+    txn.commit()?;
+    info!("dropped schema");
+    Ok(())
+}
+
+/// Parse a seed specification on the form `FILES:LINES`.
+fn parse_seed(spec: &str) -> Result<(u32, u32), String> {
+    let (files, lines) = spec
+        .split_once(':')
+        .ok_or_else(|| format!("expected FILES:LINES, got {:?}", spec))?;
+    let files = files
+        .parse()
+        .map_err(|err| format!("bad file count: {}", err))?;
+    let lines = lines
+        .parse()
+        .map_err(|err| format!("bad line count: {}", err))?;
+    Ok((files, lines))
+}
+
+/// Parse a delimiter, which is either a single ASCII character or one
+/// of the escapes `\0`, `\n`, `\r`, `\t`, and `\xHH`.
+///
+/// Records are stored as text, so the delimiter has to be ASCII.
+fn parse_delimiter(spec: &str) -> Result<u8, String> {
+    let byte = match spec {
+        "\\0" => 0,
+        "\\n" => b'\n',
+        "\\r" => b'\r',
+        "\\t" => b'\t',
+        _ => match spec.strip_prefix("\\x") {
+            Some(hex) => u8::from_str_radix(hex, 16)
+                .map_err(|err| format!("bad delimiter {:?}: {}", spec, err))?,
+            None if spec.len() == 1 => spec.as_bytes()[0],
+            None => return Err(format!("expected a single character, got {:?}", spec)),
+        },
+    };
+    if !byte.is_ascii() {
+        return Err(format!("delimiter {:?} is not ASCII", spec));
+    }
+    Ok(byte)
+}
+
+/// Parse a mount specification on the form `PATH=PARAMS`.
+///
+/// The path ends at the first `=`, so the connection parameters can
+/// contain `=` but the path cannot.
+fn parse_mount(spec: &str) -> Result<(String, String), String> {
+    let (path, params) = spec
+        .split_once('=')
+        .ok_or_else(|| format!("expected PATH=PARAMS, got {:?}", spec))?;
+    Ok((path.to_string(), params.to_string()))
+}
+
+/// Statement to create the table with the version of the schema.
+const SCHEMA_VERSION_CREATE: &str =
+    "CREATE TABLE IF NOT EXISTS schema_version (version int NOT NULL)";
+
+/// Statements to upgrade the schema, one entry for each version. The
+/// version of the schema is the number of entries applied, so new
+/// entries should only be added at the end.
+const MIGRATIONS: &[&[&str]] = &[
+    // Version 1: initial schema
+    &[
+        "CREATE TABLE inodes (ino serial, name name, mode int, uid int, gid int)",
+        "CREATE TABLE content (ino int, line text)",
+    ],
+    // Version 2: sequence number to identify and order lines
+    &["ALTER TABLE content ADD COLUMN seq bigserial"],
+    // Version 3: modification time of files
+    &["ALTER TABLE inodes ADD COLUMN mtime timestamptz NOT NULL DEFAULT now()"],
+    // Version 4: position of lines in the file, for idempotent writes
+    &[
+        "ALTER TABLE content ADD COLUMN pos bigint",
+        "CREATE UNIQUE INDEX content_ino_pos ON content (ino, pos)",
+    ],
+    // Version 5: user and process writing each line
+    &["ALTER TABLE content ADD COLUMN uid int, ADD COLUMN pid int"],
+    // Version 6: creation time of files, which is not known for files
+    // created before this version
+    &[
+        "ALTER TABLE inodes ADD COLUMN crtime timestamptz",
+        "ALTER TABLE inodes ALTER COLUMN crtime SET DEFAULT now()",
+    ],
+    // Version 7: size and change time of files, where the change time
+    // is not known for files created before this version
+    &[
+        "ALTER TABLE inodes ADD COLUMN size bigint NOT NULL DEFAULT 0, \
+         ADD COLUMN ctime timestamptz",
+        "ALTER TABLE inodes ALTER COLUMN ctime SET DEFAULT now()",
+    ],
+    // Version 8: generation of inodes, which is the time of creation in
+    // microseconds so that it changes when an inode number is reused,
+    // even after the tables have been recreated
+    &[
+        "ALTER TABLE inodes ADD COLUMN generation bigint NOT NULL DEFAULT 0",
+        "ALTER TABLE inodes ALTER COLUMN generation \
+         SET DEFAULT (extract(epoch FROM clock_timestamp()) * 1000000)::bigint",
+    ],
+    // Version 9: time each line was written, which is not known for
+    // lines written before this version
+    &[
+        "ALTER TABLE content ADD COLUMN written timestamptz",
+        "ALTER TABLE content ALTER COLUMN written SET DEFAULT now()",
+    ],
+    // Version 10: dictionary of distinct lines, which lines are stored
+    // in instead of the content table when deduplicating. Lines can be
+    // longer than fits in an index entry, so the lines are unique on
+    // their hash.
+    &[
+        "CREATE TABLE dictionary (id bigserial PRIMARY KEY, hash bytea NOT NULL UNIQUE, \
+         line text NOT NULL)",
+        "ALTER TABLE content ADD COLUMN dict_id bigint",
+    ],
+];
+
+/// Statement to insert a line into the content table.
+///
+/// With idempotent writes, each line is keyed by its byte position in
+/// the file, so replaying a write that has already been stored is a
+/// no-op. Otherwise the position is NULL, which never conflicts.
+const CONTENT_INSERT: &str = "INSERT INTO content(ino, line, pos, uid, pid) \
+     VALUES ($1,$2,$3,$4,$5) ON CONFLICT (ino, pos) DO NOTHING";
+
+/// Statement to insert a line into the dictionary, unless it is already
+/// there, and a reference to it into the content table. It takes the
+/// same parameters as `CONTENT_INSERT`.
+///
+/// An existing entry is not returned by the insert, so it is looked up
+/// instead, which avoids writing a new version of the entry for every
+/// duplicate line. The lookup does not see an entry inserted by another
+/// connection after the statement started, so lines are only
+/// deduplicated correctly when a single connection writes at a time,
+/// which is how db-fuse writes.
+const DEDUP_CONTENT_INSERT: &str = "WITH inserted AS (\
+     INSERT INTO dictionary(hash, line) VALUES (sha256(convert_to($2, 'UTF8')), $2) \
+     ON CONFLICT DO NOTHING \
+     RETURNING id), \
+     entry AS (\
+     SELECT id FROM inserted \
+     UNION ALL \
+     SELECT id FROM dictionary WHERE hash = sha256(convert_to($2, 'UTF8'))) \
+     INSERT INTO content(ino, dict_id, pos, uid, pid) \
+     SELECT $1, id, $3, $4, $5 FROM entry ON CONFLICT (ino, pos) DO NOTHING";
+
+/// Statement to use to insert lines.
+fn content_insert(dedup: bool) -> &'static str {
+    if dedup {
+        DEDUP_CONTENT_INSERT
+    } else {
+        CONTENT_INSERT
+    }
+}
+
+/// Content table joined with the dictionary, and the line of a row in
+/// it, which is in one of the tables depending on whether it was
+/// written with deduplication.
+const CONTENT_JOIN: &str = "content c LEFT JOIN dictionary d ON d.id = c.dict_id";
+const CONTENT_LINE: &str = "coalesce(c.line, d.line)";
+
+/// Expression counting the lines in the records selected from
+/// `CONTENT_JOIN`.
+///
+/// A record holds up to `format.lines` lines with the delimiters
+/// between them, so the lines are counted by splitting the records.
+/// Empty lines are skipped when writing unless they are kept, so they
+/// are not counted either.
+fn line_count_expr(format: RecordFormat) -> String {
+    if format.lines == 1 {
+        return "count(*)".to_string();
+    }
+    let lines = format!(
+        "string_to_array({}, chr({}))",
+        CONTENT_LINE, format.delimiter
+    );
+    let lines = if format.keep_empty {
+        lines
+    } else {
+        format!("array_remove({}, '')", lines)
+    };
+    format!("coalesce(sum(cardinality({})), 0)", lines)
+}
+
+/// Quote a name for use as an identifier in an SQL statement.
+fn quote_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Quote a string as an SQL literal.
+fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Statements to set the owner of the tables and grant read access to
+/// them.
+fn privilege_statements(options: &Options) -> Vec<String> {
+    let mut tables = vec!["inodes", "content", "dictionary", "schema_version"];
+    if options.create_views {
+        tables.push("files");
+    }
+    let mut statements = Vec::new();
+    if let Some(owner) = &options.table_owner {
+        for table in &tables {
+            statements.push(format!(
+                "ALTER TABLE {} OWNER TO {}",
+                table,
+                quote_ident(owner)
+            ));
+        }
+    }
+    for reader in &options.readers {
+        statements.push(format!(
+            "GRANT SELECT ON {} TO {}",
+            tables.join(", "),
+            quote_ident(reader)
+        ));
+    }
+    statements
+}
+
+/// Statements applying the options that can change between mounts to
+/// the tables.
+///
+/// These are run on every start, after the schema is upgraded, so
+/// changing the options for an existing schema takes effect the next
+/// time it is mounted. Partitioned tables have no storage of their
+/// own, so the persistence is set on the `leaves` of the tables, which
+/// are the tables themselves when they are not partitioned.
+fn configure_statements(options: &Options, leaves: &[String]) -> Vec<String> {
+    let mut statements = Vec::new();
+    let persistence = if options.unlogged {
+        "UNLOGGED"
+    } else {
+        "LOGGED"
+    };
+    for table in leaves {
+        statements.push(format!("ALTER TABLE {} SET {}", table, persistence));
+    }
+    // Partitioned tables do not have storage parameters of their own,
+    // so these are set on each partition as well.
+    if !options.storage_parameters.is_empty() {
+        for table in leaves {
+            statements.push(format!(
+                "ALTER TABLE {} SET ({})",
+                table,
+                options.storage_parameters.join(", ")
+            ));
+        }
+    }
+    if options.create_views {
+        statements.push(files_view(options.delimiter));
+    }
+    statements.extend(privilege_statements(options));
+    statements
+}
+
+/// Statements creating the schema in an empty database, in the order
+/// they would be run when mounting with the options.
+///
+/// The migrations and partitioning depend on what is already in the
+/// database, so mounting runs those itself, but the other statements
+/// are shared with `DatabaseFS::new`.
+fn schema_statements(options: &Options) -> Vec<String> {
+    let mut statements = session_statements(options);
+    statements.push(SCHEMA_VERSION_CREATE.to_string());
+    for migration in MIGRATIONS {
+        statements.extend(migration.iter().map(|statement| statement.to_string()));
+    }
+    statements.push(format!(
+        "INSERT INTO schema_version VALUES ({})",
+        MIGRATIONS.len()
+    ));
+    let mut leaves = Vec::new();
+    for (table, serial) in PARTITIONED_TABLES {
+        match options.partitions {
+            Some(count) => {
+                statements.extend(partition_statements(table, serial, count));
+                leaves.extend((0..count).map(|remainder| format!("{}_{}", table, remainder)));
+            }
+            None => leaves.push(table.to_string()),
+        }
+    }
+    statements.extend(configure_statements(options, &leaves));
+    statements.extend(row_statements(options));
+    statements
+}
+
+/// Statements setting up the session before the schema is created.
+fn session_statements(options: &Options) -> Vec<String> {
+    let mut statements = Vec::new();
+    if let Some(tablespace) = &options.tablespace {
+        statements.push(format!(
+            "SET default_tablespace = {}",
+            quote_ident(tablespace)
+        ));
+    }
+    statements
+}
+
+/// Statements adding the rows that have to exist before mounting and
+/// moving the inode sequence to `--inode-start`.
+///
+/// The root directory is stored like any other inode, using the inode
+/// that the kernel uses for it. Files are never allocated inodes below
+/// `MIN_INODE_START`, so it cannot be taken. It is owned by the user
+/// running the file system.
+fn row_statements(options: &Options) -> Vec<String> {
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    let mut statements = vec![
+        format!(
+            "SELECT setval('inodes_ino_seq', GREATEST({}, max(ino) + 1), false) FROM inodes",
+            options.inode_start
+        ),
+        format!(
+            "INSERT INTO inodes(ino, name, mode, uid, gid) SELECT {0}, '/', {1}, {2}, {3} \
+             WHERE NOT EXISTS (SELECT FROM inodes WHERE ino = {0})",
+            FUSE_ROOT_ID,
+            libc::S_IFDIR | 0o755,
+            uid,
+            gid
+        ),
+    ];
+    if options.stream_mode {
+        statements.push(format!(
+            "INSERT INTO inodes(name, mode, uid, gid) SELECT {0}, {1}, {2}, {3} \
+             WHERE NOT EXISTS (SELECT FROM inodes WHERE name = {0})",
+            quote_literal(STREAM_FILE),
+            libc::S_IFREG | options.default_file_mode,
+            uid,
+            gid
+        ));
+    }
+    statements
+}
+
+/// Statements dropping the schema created by `schema_statements`.
+/// Indexes, sequences, and partitions are dropped with their tables.
+fn drop_statements() -> Vec<String> {
+    let mut statements = vec!["DROP VIEW IF EXISTS files".to_string()];
+    for (table, _) in PARTITIONED_TABLES {
+        statements.push(format!("DROP TABLE IF EXISTS {}", table));
+    }
+    statements.push("DROP TABLE IF EXISTS dictionary".to_string());
+    statements.push("DROP TABLE IF EXISTS schema_version".to_string());
+    statements
+}
+
+/// View with the full contents of each file, with the lines joined in
+/// the order they were written.
+///
+/// The view is replaced on every mount, so the lines are joined with
+/// the delimiter of the latest mount.
+fn files_view(delimiter: u8) -> String {
+    format!(
+        "CREATE OR REPLACE VIEW files AS \
+         SELECT i.name, string_agg(coalesce(c.line, d.line), chr({}) ORDER BY c.seq) AS contents \
+         FROM inodes i JOIN content c ON c.ino = i.ino \
+         LEFT JOIN dictionary d ON d.id = c.dict_id GROUP BY i.ino, i.name",
+        delimiter
+    )
+}
+
+/// Statement to update the times and the size of an inode after a
+/// write ending at the given byte position. Writes never shrink a
+/// file, so replaying a write leaves the size as it is.
+const MTIME_UPDATE: &str = "UPDATE inodes \
+     SET mtime = now(), ctime = now(), size = GREATEST(size, $2) WHERE ino = $1";
+
+/// Columns with the attributes of an inode.
+const INODE_COLUMNS: &str = "ino, uid, gid, mode, size, mtime, ctime, crtime, generation";
+
+/// Create the schema, or upgrade an existing schema to the current
+/// version.
+///
+/// The version of the schema is stored in the `schema_version` table.
+/// Tables created before the version was tracked are treated as
+/// version 1. Schemas newer than what this binary knows about are
+/// refused since the binary cannot know how to use them.
+fn migrate(client: &mut Client) -> Result<(), Box<dyn Error>> {
+    let mut txn = client.transaction()?;
+    txn.execute(SCHEMA_VERSION_CREATE, &[])?;
+    let current: i32 = match txn.query_opt("SELECT version FROM schema_version", &[])? {
+        Some(row) => row.get("version"),
+        None => {
+            let row = txn.query_one("SELECT to_regclass('inodes') IS NOT NULL", &[])?;
+            let version = if row.get(0) { 1 } else { 0 };
+            txn.execute("INSERT INTO schema_version VALUES ($1)", &[&version])?;
+            version
+        }
+    };
+
+    let latest = MIGRATIONS.len() as i32;
+    if current > latest {
+        return Err(format!(
+            "schema version {} is newer than supported version {}",
+            current, latest
+        )
+        .into());
+    }
+
+    for (version, statements) in MIGRATIONS.iter().enumerate().skip(current as usize) {
+        info!("upgrading schema to version {}", version + 1);
+        for statement in statements.iter() {
+            txn.execute(*statement, &[])?;
+        }
+    }
+    txn.execute("UPDATE schema_version SET version = $1", &[&latest])?;
+    txn.commit()?;
+    Ok(())
+}
+
+/// Tables that can be partitioned on the inode, with the column
+/// using a sequence owned by the table.
+const PARTITIONED_TABLES: &[(&str, &str)] = &[("inodes", "ino"), ("content", "seq")];
+
+/// Statements to replace a table with a table hash partitioned on the
+/// inode, moving the rows to the partitions.
+fn partition_statements(table: &str, serial: &str, count: u32) -> Vec<String> {
+    let mut statements = vec![
+        format!("ALTER TABLE {0} RENAME TO {0}_unpartitioned", table),
+        format!(
+            "CREATE TABLE {0} (LIKE {0}_unpartitioned INCLUDING ALL) PARTITION BY HASH (ino)",
+            table
+        ),
+    ];
+    for remainder in 0..count {
+        statements.push(format!(
+            "CREATE TABLE {0}_{1} PARTITION OF {0} FOR VALUES WITH (MODULUS {2}, REMAINDER {1})",
+            table, remainder, count
+        ));
+    }
+    statements.push(format!(
+        "INSERT INTO {0} SELECT * FROM {0}_unpartitioned",
+        table
+    ));
+    // The sequence is dropped together with the table owning it, so
+    // hand it over to the new table before dropping the old one. The
+    // tables are renamed, but the sequence keeps the name it was
+    // created with.
+    statements.push(format!(
+        "ALTER SEQUENCE {0}_{1}_seq OWNED BY {0}.{1}",
+        table, serial
+    ));
+    statements.push(format!("DROP TABLE {}_unpartitioned", table));
+    statements
+}
+
+/// Hash partition the tables on the inode, moving any existing rows to
+/// the partitions.
+///
+/// Tables that are already partitioned are left as they are, even if
+/// the number of partitions is different, since changing it requires
+/// moving all rows.
+fn partition_tables(client: &mut Client, count: u32) -> Result<(), Box<dyn Error>> {
+    let mut txn = client.transaction()?;
+    for (table, serial) in PARTITIONED_TABLES {
+        let row = txn.query_one(
+            "SELECT c.relkind = 'p', (SELECT count(*) FROM pg_inherits WHERE inhparent = c.oid) \
+             FROM pg_class c WHERE c.oid = to_regclass($1)",
+            &[table],
+        )?;
+        let partitioned: bool = row.get(0);
+        let current: i64 = row.get(1);
+        if partitioned {
+            if current != count as i64 {
+                warn!(
+                    "table {} has {} partitions, not changing it to {}",
+                    table, current, count
+                );
+            }
+            continue;
+        }
+
+        info!("partitioning table {} into {} partitions", table, count);
+        // The view depends on the table being replaced. It is created
+        // again later if views are enabled.
+        txn.batch_execute("DROP VIEW IF EXISTS files")?;
+        for statement in partition_statements(table, serial, count) {
+            txn.batch_execute(&statement)?;
+        }
+    }
+    txn.commit()?;
+    Ok(())
+}
+
+/// How the data written to a file is split into records.
+#[derive(Clone, Copy)]
+struct RecordFormat {
+    /// Byte separating the lines.
+    delimiter: u8,
+    /// Keep empty lines instead of skipping them.
+    keep_empty: bool,
+    /// Number of lines stored together in a record.
+    lines: usize,
+}
+
+/// Split data into records of `format.lines` lines each, with the
+/// lines split as described for `lines::split`.
+///
+/// The lines of a record are kept as they were written, including the
+/// delimiters between them. Records never span writes, so the last
+/// record of a write can have fewer lines. Each record is returned
+/// together with its byte position in the file, given that the data
+/// was written at `offset`.
+fn split_lines(
+    offset: i64,
+    data: &[u8],
+    format: RecordFormat,
+) -> Result<Vec<(i64, &str)>, Utf8Error> {
+    let mut records = Vec::new();
+    let mut record: Option<(usize, usize)> = None;
+    let mut count = 0;
+    for (start, line) in lines::split(data, format.delimiter, format.keep_empty) {
+        let first = record.map_or(start, |(first, _)| first);
+        record = Some((first, start + line.len()));
+        count += 1;
+        if count == format.lines {
+            if let Some((first, last)) = record.take() {
+                records.push((offset + first as i64, from_utf8(&data[first..last])?));
+            }
+            count = 0;
+        }
+    }
+    if let Some((first, last)) = record {
+        records.push((offset + first as i64, from_utf8(&data[first..last])?));
+    }
+    Ok(records)
+}
+
+/// Position to store for a line, which is only recorded for
+/// idempotent writes.
+fn line_position(idempotent: bool, pos: i64) -> Option<i64> {
+    if idempotent {
+        Some(pos)
+    } else {
+        None
+    }
+}
+
+/// Error numbers for SQL states that have a natural counterpart. Any
+/// other error is reported as `EIO`.
+const SQLSTATE_ERRNO: &[(SqlState, c_int)] = &[
+    (SqlState::UNIQUE_VIOLATION, libc::EEXIST),
+    (SqlState::NOT_NULL_VIOLATION, libc::EINVAL),
+    (SqlState::CHECK_VIOLATION, libc::EINVAL),
+    (SqlState::INVALID_TEXT_REPRESENTATION, libc::EINVAL),
+    (SqlState::CHARACTER_NOT_IN_REPERTOIRE, libc::EINVAL),
+    (SqlState::NUMERIC_VALUE_OUT_OF_RANGE, libc::ERANGE),
+    (SqlState::STRING_DATA_RIGHT_TRUNCATION, libc::ENAMETOOLONG),
+    (SqlState::PROGRAM_LIMIT_EXCEEDED, libc::EFBIG),
+    (SqlState::INSUFFICIENT_PRIVILEGE, libc::EACCES),
+    (SqlState::READ_ONLY_SQL_TRANSACTION, libc::EROFS),
+    (SqlState::DISK_FULL, libc::ENOSPC),
+    (SqlState::OUT_OF_MEMORY, libc::ENOMEM),
+    (SqlState::QUERY_CANCELED, libc::EINTR),
+    (SqlState::LOCK_NOT_AVAILABLE, libc::EAGAIN),
+    (SqlState::T_R_SERIALIZATION_FAILURE, libc::EAGAIN),
+    (SqlState::T_R_DEADLOCK_DETECTED, libc::EDEADLK),
+];
+
+/// Log a database error and map it to the error number to report to
+/// the kernel.
+///
+/// Errors without an SQL state, such as a closed connection, and
+/// connection exceptions (class 08) are reported as `EIO`.
+fn pg_to_errno(err: &postgres::Error) -> c_int {
+    error!("query error: {}", err);
+    let state = match err.code() {
+        Some(state) => state,
+        None => return libc::EIO,
+    };
+    SQLSTATE_ERRNO
+        .iter()
+        .find(|(known, _)| known == state)
+        .map_or(libc::EIO, |(_, errno)| *errno)
+}
+
+/// Insert lines into the content table and update the modification
+/// time and size of the inode, returning the number of rows inserted.
+/// The write ends at byte position `end` of the file.
+fn insert_lines<C: GenericClient>(
+    client: &mut C,
+    content_insert: &Statement,
+    idempotent: bool,
+    ino: i32,
+    end: i64,
+    writer: Option<Writer>,
+    lines: &[(i64, &str)],
+) -> Result<u64, postgres::Error> {
+    let (uid, pid) = Writer::columns(writer);
+    let mut rows = 0;
+    for (pos, line) in lines {
+        let pos = line_position(idempotent, *pos);
+        rows += client.execute(content_insert, &[&ino, line, &pos, &uid, &pid])?;
+    }
+    client.execute(MTIME_UPDATE, &[&ino, &end])?;
+    Ok(rows)
+}
+
+/// Number of times a statement is retried after a transient error, and
+/// the delay before the first retry. The delay doubles for each retry.
+const MAX_RETRIES: u32 = 5;
+const RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// Interval between attempts to write to the database after it has
+/// been found to be unwritable with `--degrade-to-readonly`. Writes in
+/// between are rejected without trying them.
+const WRITE_PROBE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Run a database operation, retrying it with exponential backoff if
+/// it fails with an error that is likely to go away when retried.
+///
+/// The operation has to be safe to run again after such an error,
+/// which is the case for a single statement outside a transaction or
+/// for a whole transaction.
+fn with_retry<T>(
+    mut operation: impl FnMut() -> Result<T, postgres::Error>,
+) -> Result<T, postgres::Error> {
+    let mut delay = RETRY_DELAY;
+    let mut retries = 0;
+    loop {
+        match operation() {
+            Err(err) if retries < MAX_RETRIES && is_transient(&err) => {
+                retries += 1;
+                debug!("retry {} in {:?} after error: {}", retries, delay, err);
+                thread::sleep(delay);
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Check if an error is a serialization failure or deadlock, which
+/// are expected under contention and can be retried.
+fn is_transient(err: &postgres::Error) -> bool {
+    matches!(
+        err.code(),
+        Some(state) if *state == SqlState::T_R_SERIALIZATION_FAILURE
+            || *state == SqlState::T_R_DEADLOCK_DETECTED
+    )
+}
+
+/// Number of 512-byte blocks needed for `size` bytes, as reported in
+/// `st_blocks`.
+fn blocks(size: u64) -> u64 {
+    size.div_ceil(512)
+}
+
+fn new_attr(ino: i64, uid: u32, gid: u32, mode: u32, blksize: u32) -> FileAttr {
+    let is_dir = mode & libc::S_IFMT == libc::S_IFDIR;
+    FileAttr {
+        ino: ino as u64,
+        size: 0,
+        atime: SystemTime::now(),
+        mtime: SystemTime::now(),
+        ctime: SystemTime::now(),
         crtime: SystemTime::UNIX_EPOCH,
-        kind: FileType::RegularFile,
-        perm: mode as u16,
-        nlink: 1,
+        kind: if is_dir {
+            FileType::Directory
+        } else {
+            FileType::RegularFile
+        },
+        perm: (mode & 0o7777) as u16,
+        nlink: if is_dir { 2 } else { 1 },
         uid,
         gid,
         rdev: 0,
         blocks: 0,
         flags: 0,
-        blksize: BLOCK_SIZE as u32,
+        blksize,
+    }
+}
+
+/**
+ * Health of the file system.
+ *
+ * This is shared with the background writer and reported through the
+ * virtual status file, so it only contains in-memory counters.
+ */
+#[derive(Default)]
+struct Status {
+    last_error: Mutex<Option<String>>,
+    /// Most recent database errors of operations on the mount, oldest
+    /// first, with at most `max_errors` kept.
+    errors: Mutex<VecDeque<String>>,
+    max_errors: usize,
+    pending_writes: AtomicU64,
+    rows_committed: AtomicU64,
+    /// Number of batches of queued writes committed, and the time the
+    /// last one took.
+    commits: AtomicU64,
+    last_commit_micros: AtomicU64,
+    /// Time of the last operation on the mount, in microseconds since
+    /// the epoch.
+    last_activity_micros: AtomicU64,
+}
+
+impl Status {
+    /// Note that an operation was made on the mount.
+    fn touch(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.last_activity_micros
+            .store(now.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Time since the last operation on the mount.
+    fn idle_time(&self) -> Duration {
+        let last =
+            UNIX_EPOCH + Duration::from_micros(self.last_activity_micros.load(Ordering::Relaxed));
+        SystemTime::now().duration_since(last).unwrap_or_default()
+    }
+
+    fn record_error(&self, err: &dyn Display) {
+        *self.last_error.lock().unwrap() = Some(err.to_string());
+    }
+
+    /// Keep a database error of an operation for the `.errors` file,
+    /// dropping the oldest error if there are too many.
+    fn log_error(&self, op: &str, ino: u64, errno: c_int, err: &postgres::Error) {
+        if self.max_errors == 0 {
+            return;
+        }
+        let mut errors = self.errors.lock().unwrap();
+        if errors.len() == self.max_errors {
+            errors.pop_front();
+        }
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        errors.push_back(format!(
+            "time={} op={} ino={} errno={}: {}\n",
+            time,
+            op,
+            ino,
+            std::io::Error::from_raw_os_error(errno),
+            err.to_string().replace('\n', " ")
+        ));
+    }
+
+    fn errors_report(&self) -> String {
+        self.errors
+            .lock()
+            .unwrap()
+            .iter()
+            .map(String::as_str)
+            .collect()
+    }
+
+    fn report(&self) -> String {
+        let last_error = self.last_error.lock().unwrap();
+        format!(
+            "last_error: {}\npending_writes: {}\nrows_committed: {}\ncommits: {}\nlast_commit_micros: {}\n",
+            last_error.as_deref().unwrap_or("none"),
+            self.pending_writes.load(Ordering::Relaxed),
+            self.rows_committed.load(Ordering::Relaxed),
+            self.commits.load(Ordering::Relaxed),
+            self.last_commit_micros.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// What to do when writing to a full write queue.
+#[derive(Clone, Copy, Default)]
+enum QueueFullPolicy {
+    /// Block the writer until there is room in the queue.
+    #[default]
+    Block,
+    /// Fail the write with `EAGAIN`.
+    Fail,
+}
+
+/// When the data written to a file is inserted into the database.
+#[derive(Clone, Copy, Default, PartialEq)]
+enum WritePolicy {
+    /// Insert the data of each write before replying to it.
+    #[default]
+    Immediate,
+    /// Keep the data written through a handle in memory and insert it
+    /// in a single transaction when the handle is flushed or released.
+    OnClose,
+}
+
+/// How the captured content is presented in the file system.
+#[derive(Clone, Copy, PartialEq)]
+enum Layout {
+    /// Each captured file is a regular file with all its lines.
+    Files,
+    /// In addition to the regular files, there is a directory for
+    /// each file in the `.records` directory, with one file for each
+    /// record.
+    RecordFiles,
+}
+
+/// Node in the record-files layout.
+enum RecordNode {
+    /// The `.records` directory.
+    Root,
+    /// Directory with the records of the file with the inode.
+    Stream(i32),
+    /// File with the record with the sequence number.
+    Record(i64),
+}
+
+impl RecordNode {
+    fn from_inode(ino: u64) -> Option<RecordNode> {
+        if ino == RECORDS_INODE {
+            Some(RecordNode::Root)
+        } else if ino & RECORD_FLAG != 0 {
+            Some(RecordNode::Record((ino & !RECORD_FLAG) as i64))
+        } else if ino & STREAM_FLAG != 0 {
+            Some(RecordNode::Stream((ino & !STREAM_FLAG) as i32))
+        } else {
+            None
+        }
+    }
+}
+
+/// Attributes for a directory that cannot be modified.
+fn read_only_dir_attr(ino: u64, uid: u32, gid: u32) -> FileAttr {
+    FileAttr {
+        ino,
+        perm: 0o555,
+        uid,
+        gid,
+        ..CAPTURE_DIR_ATTR
+    }
+}
+
+/// Options for the database file system.
+#[derive(Clone)]
+struct Options {
+    /// Size of the write queue, if writes should be inserted into the
+    /// database asynchronously.
+    write_queue_size: Option<usize>,
+    /// Interval to collect queued writes for before committing them,
+    /// if they should be committed in batches.
+    commit_interval: Option<Duration>,
+    queue_full_policy: QueueFullPolicy,
+    /// Maximum number of bytes in a single line.
+    max_line_bytes: usize,
+    /// Maximum number of bytes in a file, if limited.
+    max_file_size: Option<u64>,
+    /// Byte separating the lines written to a file.
+    delimiter: u8,
+    /// Store empty lines instead of skipping them.
+    keep_empty_lines: bool,
+    /// Number of lines stored together in each record.
+    record_lines: usize,
+    /// Maximum size of a write request from the kernel.
+    max_write: u32,
+    /// Maximum number of bytes the kernel reads ahead of sequential
+    /// reads, if not the kernel default. Files opened with direct I/O,
+    /// such as the virtual files, are never read ahead.
+    max_readahead: Option<u32>,
+    /// Tell the kernel that lookups of "." and ".." are handled, which
+    /// is needed to export the file system over NFS.
+    export_support: bool,
+    /// First inode to allocate for files. Inodes already in use are
+    /// never reused, so with existing tables allocation continues
+    /// after the highest inode in use if that is larger.
+    inode_start: i32,
+    /// Storage parameters to set on the tables, as `NAME = VALUE`.
+    /// Parameters that are not given are left as they are.
+    storage_parameters: Vec<String>,
+    /// Make the tables unlogged, which makes writes faster since they
+    /// skip the write-ahead log, but the tables are emptied after a
+    /// crash of the database server.
+    unlogged: bool,
+    /// Number of hash partitions for the tables, if they should be
+    /// partitioned on the inode.
+    partitions: Option<u32>,
+    /// Application name reported to the database, which shows up in
+    /// `pg_stat_activity`.
+    application_name: Option<String>,
+    /// SQL statements to execute after connecting, before the tables
+    /// are created or any statements are prepared.
+    ///
+    /// They are only executed once, on the main connection, so session
+    /// settings do not apply to the connection used for asynchronous
+    /// writes.
+    init_sql: Option<String>,
+    layout: Layout,
+    /// List the `.stats` directory in the root directory. It can be
+    /// used also when not listed.
+    show_stats: bool,
+    /// Only have the stream file, which is created if it does not
+    /// exist. Other files stored in the tables are hidden and no
+    /// other files can be created.
+    stream_mode: bool,
+    /// Connection parameters for a read replica. Lookups, directory
+    /// listings, and reads go to the replica, while writes and
+    /// everything needed to write go to the primary.
+    read_params: Option<String>,
+    /// Skip lines that are already stored at the same position, so
+    /// that replaying writes does not duplicate content.
+    idempotent_writes: bool,
+    /// Store the lines in the dictionary table, so that identical lines
+    /// are stored only once.
+    dedup: bool,
+    /// Record the user and process that wrote each line.
+    annotate_writer: bool,
+    write_policy: WritePolicy,
+    /// Commit each write in a transaction of its own and wait for the
+    /// commit to be flushed to disk before replying to the write.
+    sync_writes: bool,
+    /// Reject writes without trying them while the database cannot be
+    /// written, for example because it is in recovery or the disk is
+    /// full.
+    degrade_to_readonly: bool,
+    /// Number of database errors to keep for the `.errors` file, if
+    /// it should be available. The errors can reveal details about the
+    /// database, so the file is not there by default.
+    debug_errors: Option<usize>,
+    /// Create views that present the captured content in a form that
+    /// is easier to query from SQL.
+    create_views: bool,
+    /// Tablespace to create the tables and indexes in, if not the
+    /// default tablespace of the database. Tables that already exist
+    /// are not moved.
+    tablespace: Option<String>,
+    /// Role to own the tables, if not the role used to connect.
+    ///
+    /// The connecting role needs to be a member of this role, both to
+    /// change the owner and to keep using the tables afterwards.
+    table_owner: Option<String>,
+    /// Roles to grant read access to the captured data.
+    ///
+    /// The connecting role needs to own the tables, directly or
+    /// through membership of the table owner.
+    readers: Vec<String>,
+    /// Permissions to use for files that are created with no
+    /// permission bits set, which would otherwise be inaccessible.
+    default_file_mode: u32,
+    /// Maximum number of open file handles, if limited.
+    max_open_files: Option<usize>,
+    /// Preferred I/O size reported for files.
+    io_block_size: u32,
+}
+
+/// User and process that wrote some data.
+#[derive(Clone, Copy)]
+struct Writer {
+    uid: u32,
+    pid: u32,
+}
+
+impl From<&Request<'_>> for Writer {
+    fn from(req: &Request) -> Writer {
+        Writer {
+            uid: req.uid(),
+            pid: req.pid(),
+        }
+    }
+}
+
+impl Writer {
+    /// Column values for the writer of a line, which are NULL when
+    /// writers are not annotated.
+    fn columns(writer: Option<Writer>) -> (Option<i32>, Option<i32>) {
+        match writer {
+            Some(writer) => (Some(writer.uid as i32), Some(writer.pid as i32)),
+            None => (None, None),
+        }
+    }
+}
+
+/// An open file.
+struct Handle {
+    ino: u64,
+    flags: i32,
+    /// Writes that are not yet inserted, with the position and the
+    /// writer of each, when writes are inserted on close.
+    pending: Vec<(i64, Vec<u8>, Option<Writer>)>,
+}
+
+impl Handle {
+    fn report(&self, fh: u64) -> String {
+        let access = match self.flags & libc::O_ACCMODE {
+            libc::O_RDONLY => "r",
+            libc::O_WRONLY => "w",
+            _ => "rw",
+        };
+        format!(
+            "fh={} ino={} access={} append={}\n",
+            fh,
+            self.ino,
+            access,
+            self.flags & libc::O_APPEND != 0
+        )
+    }
+}
+
+/// Requests handled by the background writer.
+enum WriteRequest {
+    /// Write data to an inode.
+    Write(i32, i64, Vec<u8>, Option<Writer>),
+    /// Signal the sender once all preceding writes are done.
+    Flush(SyncSender<()>),
+}
+
+/// Lines of a write that are not yet committed to the database.
+struct PendingWrite {
+    ino: i32,
+    /// Byte position in the file where the write ends.
+    end: i64,
+    writer: Option<Writer>,
+    lines: Vec<(i64, String)>,
+}
+
+/**
+ * Background writer inserting lines into the database.
+ *
+ * Writes are put in a bounded queue and inserted into the database by
+ * a worker thread using a separate connection, so that the writer does
+ * not have to wait for the database. Each write is committed in a
+ * transaction of its own unless a commit interval is given, in which
+ * case the writes received during the interval are committed together.
+ *
+ * When the writer is dropped, the queue is drained and all pending
+ * writes are committed before returning.
+ */
+struct AsyncWriter {
+    sender: Option<SyncSender<WriteRequest>>,
+    worker: Option<JoinHandle<()>>,
+    policy: QueueFullPolicy,
+    status: Arc<Status>,
+}
+
+impl AsyncWriter {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        config: &Config,
+        queue_size: usize,
+        policy: QueueFullPolicy,
+        idempotent: bool,
+        dedup: bool,
+        format: RecordFormat,
+        commit_interval: Option<Duration>,
+        status: Arc<Status>,
+    ) -> Result<AsyncWriter, postgres::Error> {
+        let mut client = config.connect(NoTls)?;
+        let content_insert = client.prepare(content_insert(dedup))?;
+        let (sender, receiver) = sync_channel(queue_size);
+        let worker_status = status.clone();
+        let worker = thread::spawn(move || {
+            AsyncWriter::run(
+                client,
+                content_insert,
+                idempotent,
+                format,
+                commit_interval,
+                receiver,
+                worker_status,
+            );
+        });
+        Ok(AsyncWriter {
+            sender: Some(sender),
+            worker: Some(worker),
+            policy,
+            status,
+        })
+    }
+
+    fn run(
+        mut client: Client,
+        content_insert: Statement,
+        idempotent: bool,
+        format: RecordFormat,
+        commit_interval: Option<Duration>,
+        receiver: Receiver<WriteRequest>,
+        status: Arc<Status>,
+    ) {
+        let mut batch = Vec::new();
+        let mut deadline: Option<Instant> = None;
+        loop {
+            let request = match deadline {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    match receiver.recv_timeout(timeout) {
+                        Ok(request) => Some(request),
+                        Err(RecvTimeoutError::Timeout) => None,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                None => match receiver.recv() {
+                    Ok(request) => Some(request),
+                    Err(_) => break,
+                },
+            };
+            match request {
+                Some(WriteRequest::Write(ino, offset, data, writer)) => {
+                    match split_lines(offset, &data, format) {
+                        Ok(lines) => batch.push(PendingWrite {
+                            ino,
+                            end: offset + data.len() as i64,
+                            writer,
+                            lines: lines
+                                .into_iter()
+                                .map(|(pos, line)| (pos, line.to_string()))
+                                .collect(),
+                        }),
+                        Err(err) => {
+                            error!("dropping write to inode {}: {}", ino, err);
+                            status.record_error(&err);
+                            status.pending_writes.fetch_sub(1, Ordering::Relaxed);
+                        }
+                    }
+                    if let Some(interval) = commit_interval {
+                        deadline.get_or_insert_with(|| Instant::now() + interval);
+                        continue;
+                    }
+                }
+                Some(WriteRequest::Flush(done)) => {
+                    AsyncWriter::commit(
+                        &mut client,
+                        &content_insert,
+                        idempotent,
+                        &mut batch,
+                        &status,
+                    );
+                    deadline = None;
+                    let _ = done.send(());
+                    continue;
+                }
+                None => (),
+            }
+            AsyncWriter::commit(
+                &mut client,
+                &content_insert,
+                idempotent,
+                &mut batch,
+                &status,
+            );
+            deadline = None;
+        }
+
+        // The channel is closed, so commit whatever is left before
+        // returning.
+        AsyncWriter::commit(
+            &mut client,
+            &content_insert,
+            idempotent,
+            &mut batch,
+            &status,
+        );
+        debug!("write queue drained");
+    }
+
+    /// Insert the pending writes into the database in a single
+    /// transaction.
+    fn commit(
+        client: &mut Client,
+        content_insert: &Statement,
+        idempotent: bool,
+        batch: &mut Vec<PendingWrite>,
+        status: &Status,
+    ) {
+        if batch.is_empty() {
+            return;
+        }
+        let start = Instant::now();
+        let result = with_retry(|| {
+            let mut txn = client.transaction()?;
+            let mut rows = 0;
+            for write in batch.iter() {
+                let lines: Vec<_> = write
+                    .lines
+                    .iter()
+                    .map(|(pos, line)| (*pos, line.as_str()))
+                    .collect();
+                rows += insert_lines(
+                    &mut txn,
+                    content_insert,
+                    idempotent,
+                    write.ino,
+                    write.end,
+                    write.writer,
+                    &lines,
+                )?;
+            }
+            txn.commit()?;
+            Ok(rows)
+        });
+        match result {
+            Ok(rows) => {
+                let latency = start.elapsed();
+                info!(
+                    "committed {} rows from {} writes in {:?}",
+                    rows,
+                    batch.len(),
+                    latency
+                );
+                status.rows_committed.fetch_add(rows, Ordering::Relaxed);
+                status.commits.fetch_add(1, Ordering::Relaxed);
+                status
+                    .last_commit_micros
+                    .store(latency.as_micros() as u64, Ordering::Relaxed);
+            }
+            Err(err) => {
+                error!("dropping {} writes: {}", batch.len(), err);
+                status.record_error(&err);
+            }
+        }
+        status
+            .pending_writes
+            .fetch_sub(batch.len() as u64, Ordering::Relaxed);
+        batch.clear();
+    }
+
+    /// Queue data for writing to an inode.
+    fn send(
+        &self,
+        ino: i32,
+        offset: i64,
+        data: &[u8],
+        writer: Option<Writer>,
+    ) -> Result<(), c_int> {
+        let sender = self.sender.as_ref().ok_or(libc::EIO)?;
+        self.status.pending_writes.fetch_add(1, Ordering::Relaxed);
+        let result = match self.policy {
+            QueueFullPolicy::Block => sender
+                .send(WriteRequest::Write(ino, offset, data.to_vec(), writer))
+                .map_err(|_| libc::EIO),
+            QueueFullPolicy::Fail => {
+                match sender.try_send(WriteRequest::Write(ino, offset, data.to_vec(), writer)) {
+                    Ok(()) => Ok(()),
+                    Err(TrySendError::Full(_)) => Err(libc::EAGAIN),
+                    Err(TrySendError::Disconnected(_)) => Err(libc::EIO),
+                }
+            }
+        };
+        if result.is_err() {
+            self.status.pending_writes.fetch_sub(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// Wait for all queued writes to be written to the database.
+    fn flush(&self) -> Result<(), c_int> {
+        let sender = self.sender.as_ref().ok_or(libc::EIO)?;
+        let (done_sender, done) = sync_channel(1);
+        sender
+            .send(WriteRequest::Flush(done_sender))
+            .map_err(|_| libc::EIO)?;
+        done.recv().map_err(|_| libc::EIO)
+    }
+}
+
+impl Drop for AsyncWriter {
+    fn drop(&mut self) {
+        // Closing the channel terminates the worker once the queue is
+        // empty.
+        self.sender = None;
+        if let Some(worker) = self.worker.take() {
+            if worker.join().is_err() {
+                error!("write worker panicked");
+            }
+        }
+    }
+}
+
+/**
+ * Prepared statements for the reads that can go to a replica.
+ *
+ * Prepared statements belong to a connection, so there is one set for
+ * the primary and one for the replica.
+ */
+struct ReadStatements {
+    name_lookup: Statement,
+    inode_lookup: Statement,
+    directory_scan: Statement,
+}
+
+impl ReadStatements {
+    fn prepare(client: &mut Client) -> Result<ReadStatements, postgres::Error> {
+        Ok(ReadStatements {
+            name_lookup: client.prepare(&format!(
+                "SELECT {} FROM inodes WHERE name = $1",
+                INODE_COLUMNS
+            ))?,
+            inode_lookup: client.prepare(&format!(
+                "SELECT {} FROM inodes WHERE ino = $1",
+                INODE_COLUMNS
+            ))?,
+            directory_scan: client
+                .prepare("SELECT name, ino FROM inodes WHERE ino <> 1 ORDER BY ino")?,
+        })
     }
 }
 
@@ -156,163 +2494,1400 @@ fn new_attr(ino: i64, uid: u32, gid: u32, mode: u32) -> FileAttr {
  */
 struct DatabaseFS {
     client: Client,
-    entries: Option<Vec<postgres::Row>>,
-    name_lookup: Statement,
+    max_line_bytes: usize,
+    max_file_size: Option<u64>,
+    format: RecordFormat,
+    idempotent_writes: bool,
+    dedup: bool,
+    annotate_writer: bool,
+    write_policy: WritePolicy,
+    sync_writes: bool,
+    degrade_to_readonly: bool,
+    /// Time of the last write attempt, if the database has been found
+    /// to be unwritable and writes are rejected.
+    degraded: Option<Instant>,
+    default_file_mode: u32,
+    max_open_files: Option<usize>,
+    io_block_size: u32,
+    max_write: u32,
+    max_readahead: Option<u32>,
+    export_support: bool,
+    status: Arc<Status>,
+    last_fh: u64,
+    handles: HashMap<u64, Handle>,
+    writer: Option<AsyncWriter>,
+    layout: Layout,
+    show_stats: bool,
+    /// Inode of the stream file in stream mode.
+    stream: Option<i32>,
+    /// Snapshot of the directory entries for each open directory,
+    /// taken when the directory is opened.
+    dir_handles: HashMap<u64, Vec<(String, u64, FileType)>>,
+    /// Name of the most recently created file, which is the target of
+    /// the `latest` symbolic link.
+    latest: Option<String>,
+    statements: ReadStatements,
+    /// Connection to the read replica, if there is one, with the
+    /// statements prepared on it.
+    replica: Option<(Client, ReadStatements)>,
     content_insert: Statement,
-    inode_lookup: Statement,
     inode_insert: Statement,
-    directory_scan: Statement,
 }
 
-impl Drop for DatabaseFS {
-    fn drop(&mut self) {
-        self.client.execute("DROP TABLE inodes", &[]).unwrap();
-        self.client.execute("DROP TABLE content", &[]).unwrap();
+impl DatabaseFS {
+    fn new(params: String, options: Options) -> Result<DatabaseFS, Box<dyn Error>> {
+        let mut config: Config = params.parse()?;
+        if let Some(name) = &options.application_name {
+            config.application_name(name);
+        }
+        let mut client = config.connect(NoTls)?;
+        if let Some(sql) = &options.init_sql {
+            client
+                .batch_execute(sql)
+                .map_err(|err| format!("init SQL failed: {}", err))?;
+        }
+        if let Some(tablespace) = &options.tablespace {
+            // Setting default_tablespace to a missing tablespace only
+            // gives a warning, so check it here to give a clear error.
+            if client
+                .query_opt(
+                    "SELECT FROM pg_tablespace WHERE spcname = $1",
+                    &[tablespace],
+                )?
+                .is_none()
+            {
+                return Err(format!("tablespace {:?} does not exist", tablespace).into());
+            }
+        }
+        for statement in session_statements(&options) {
+            client.batch_execute(&statement)?;
+        }
+        migrate(&mut client)?;
+        if let Some(count) = options.partitions {
+            partition_tables(&mut client, count)?;
+        }
+        let leaves: Vec<String> = client
+            .query(
+                "SELECT relid::text FROM pg_partition_tree('inodes') WHERE isleaf \
+                 UNION ALL SELECT relid::text FROM pg_partition_tree('content') WHERE isleaf \
+                 UNION ALL SELECT oid::regclass::text FROM pg_class \
+                 WHERE oid IN ('inodes'::regclass, 'content'::regclass) AND relkind = 'r'",
+                &[],
+            )?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        for statement in configure_statements(&options, &leaves) {
+            client.batch_execute(&statement)?;
+        }
+        for statement in row_statements(&options) {
+            client.batch_execute(&statement)?;
+        }
+
+        let stream = if options.stream_mode {
+            let row =
+                client.query_one("SELECT ino FROM inodes WHERE name = $1", &[&STREAM_FILE])?;
+            Some(row.get("ino"))
+        } else {
+            None
+        };
+
+        let statements = ReadStatements::prepare(&mut client)?;
+        let replica = match &options.read_params {
+            Some(read_params) => {
+                let mut read_config: Config = read_params.parse()?;
+                if let Some(name) = &options.application_name {
+                    read_config.application_name(name);
+                }
+                let mut replica = read_config
+                    .connect(NoTls)
+                    .map_err(|err| format!("connecting to replica failed: {}", err))?;
+                // The tables have to be replicated before the
+                // statements can be prepared.
+                let statements = ReadStatements::prepare(&mut replica)
+                    .map_err(|err| format!("preparing statements on replica failed: {}", err))?;
+                Some((replica, statements))
+            }
+            None => None,
+        };
+        let content_insert = client.prepare(content_insert(options.dedup))?;
+        let inode_insert = client.prepare(
+            "INSERT INTO inodes(name, mode, uid, gid) VALUES ($1, $2, $3, $4) \
+             RETURNING ino, crtime, generation",
+        )?;
+        let latest = client
+            .query_opt(
+                "SELECT name FROM inodes WHERE ino <> 1 ORDER BY ino DESC LIMIT 1",
+                &[],
+            )?
+            .map(|row| row.get("name"));
+        let format = RecordFormat {
+            delimiter: options.delimiter,
+            keep_empty: options.keep_empty_lines,
+            lines: options.record_lines,
+        };
+        let status = Arc::new(Status {
+            max_errors: options.debug_errors.unwrap_or(0),
+            ..Status::default()
+        });
+        status.touch();
+        let writer = match options.write_queue_size {
+            Some(queue_size) => Some(AsyncWriter::new(
+                &config,
+                queue_size,
+                options.queue_full_policy,
+                options.idempotent_writes,
+                options.dedup,
+                format,
+                options.commit_interval,
+                status.clone(),
+            )?),
+            None => None,
+        };
+
+        Ok(DatabaseFS {
+            client,
+            max_line_bytes: options.max_line_bytes,
+            max_file_size: options.max_file_size,
+            format,
+            idempotent_writes: options.idempotent_writes,
+            dedup: options.dedup,
+            annotate_writer: options.annotate_writer,
+            write_policy: options.write_policy,
+            sync_writes: options.sync_writes,
+            degrade_to_readonly: options.degrade_to_readonly,
+            degraded: None,
+            default_file_mode: options.default_file_mode,
+            max_open_files: options.max_open_files,
+            io_block_size: options.io_block_size,
+            max_write: options.max_write,
+            max_readahead: options.max_readahead,
+            export_support: options.export_support,
+            status,
+            last_fh: 0,
+            handles: HashMap::new(),
+            writer,
+            layout: options.layout,
+            show_stats: options.show_stats,
+            stream,
+            dir_handles: HashMap::new(),
+            latest,
+            statements,
+            replica,
+            content_insert,
+            inode_insert,
+        })
+    }
+
+    /// Connection and statements to use for reads, which is the replica
+    /// if there is one.
+    fn reader(&mut self) -> (&mut Client, &ReadStatements) {
+        match &mut self.replica {
+            Some((client, statements)) => (client, statements),
+            None => (&mut self.client, &self.statements),
+        }
+    }
+
+    /// Read a single row, from the replica if there is one.
+    ///
+    /// A row missing on the replica is read from the primary, since a
+    /// file created through this mount may not have been replicated
+    /// yet and the kernel looks it up right after creating it.
+    fn read_opt(
+        &mut self,
+        statement: fn(&ReadStatements) -> &Statement,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Option<Row>, postgres::Error> {
+        if let Some((client, statements)) = &mut self.replica {
+            if let Some(row) = client.query_opt(statement(statements), params)? {
+                return Ok(Some(row));
+            }
+        }
+        self.client.query_opt(statement(&self.statements), params)
+    }
+
+    /// Entries of the root directory, from the replica if there is one.
+    fn scan_directory(&mut self) -> Result<Vec<Row>, postgres::Error> {
+        let (client, statements) = self.reader();
+        let mut rows = client.query(&statements.directory_scan, &[])?;
+        rows.retain(|row| self.is_visible(row.get("ino")));
+        Ok(rows)
+    }
+
+    /// Check if a file is visible, which is all files except in stream
+    /// mode, where only the stream file is.
+    fn is_visible(&self, ino: i32) -> bool {
+        self.stream.is_none_or(|stream| ino == stream)
+    }
+
+    fn is_stream(&self, ino: u64) -> bool {
+        self.stream.is_some_and(|stream| ino == stream as u64)
+    }
+
+    /// Read from the stream file, which gives back the data written to
+    /// it.
+    ///
+    /// The lines are located by their stored position, starting from
+    /// the last line at or before the offset, so only the lines
+    /// overlapping the range are transferred. The bytes between the
+    /// lines, up to the size of the stream, are delimiters, which are
+    /// not stored.
+    fn read_stream(&mut self, ino: i32, offset: i64, size: u32) -> Result<Vec<u8>, c_int> {
+        let end = offset + size as i64;
+        let (client, _) = self.reader();
+        let result = client
+            .query_one("SELECT size FROM inodes WHERE ino = $1", &[&ino])
+            .and_then(|row| {
+                let rows = client.query(
+                    &format!(
+                        "SELECT {line} AS line, c.pos FROM {join} \
+                         WHERE c.ino = $1 AND c.pos < $3 AND c.pos >= coalesce(\
+                         (SELECT pos FROM content WHERE ino = $1 AND pos <= $2 \
+                          ORDER BY pos DESC LIMIT 1), 0) \
+                         ORDER BY c.pos",
+                        line = CONTENT_LINE,
+                        join = CONTENT_JOIN
+                    ),
+                    &[&ino, &offset, &end],
+                )?;
+                Ok((row.get::<_, i64>("size"), rows))
+            });
+        let (stream_size, rows) = result.map_err(|err| self.db_errno("read", ino as u64, &err))?;
+        let end = end.min(stream_size);
+        let mut data = Vec::new();
+        let mut next = offset;
+        for row in rows {
+            let line: &str = row.get("line");
+            let pos: i64 = row.get("pos");
+            if pos > next {
+                data.resize(data.len() + (pos - next) as usize, self.format.delimiter);
+                next = pos;
+            }
+            let skip = (next - pos) as usize;
+            data.extend_from_slice(&line.as_bytes()[skip.min(line.len())..]);
+            next = next.max(pos + line.len() as i64);
+        }
+        if end > next {
+            data.resize(data.len() + (end - next) as usize, self.format.delimiter);
+        }
+        data.truncate((end - offset).max(0) as usize);
+        Ok(data)
+    }
+
+    /// Map a database error from an operation on an inode to the error
+    /// number to report, keeping it for the `.errors` file.
+    fn db_errno(&self, op: &str, ino: u64, err: &postgres::Error) -> c_int {
+        let errno = pg_to_errno(err);
+        self.status.log_error(op, ino, errno, err);
+        errno
+    }
+
+    /// Inode of the virtual file with the given name, if there is one.
+    fn virtual_inode(&self, name: &OsStr) -> Option<u64> {
+        if name == STATUS_NAME {
+            Some(STATUS_INODE)
+        } else if name == HANDLES_NAME {
+            Some(HANDLES_INODE)
+        } else if name == ERRORS_NAME && self.status.max_errors > 0 {
+            Some(ERRORS_INODE)
+        } else {
+            None
+        }
+    }
+
+    fn is_virtual(ino: u64) -> bool {
+        ino == STATUS_INODE
+            || ino == HANDLES_INODE
+            || ino == ERRORS_INODE
+            || ino == LATEST_INODE
+            || ino == STATS_INODE
+            || stats_file(ino).is_some()
+            || RecordNode::from_inode(ino).is_some()
+    }
+
+    /// Attributes of the `latest` symbolic link, or `None` if no file
+    /// has been created yet.
+    fn latest_attr(&self) -> Option<FileAttr> {
+        let target = self.latest.as_ref()?;
+        let mut attr = new_attr(LATEST_INODE as i64, 0, 0, 0o777, self.io_block_size);
+        attr.kind = FileType::Symlink;
+        attr.size = target.len() as u64;
+        attr.blocks = blocks(attr.size);
+        Some(attr)
+    }
+
+    /// Check if the inode is a directory, which can neither be read nor
+    /// written.
+    fn is_directory(&self, ino: u64) -> bool {
+        ino == FUSE_ROOT_ID
+            || ino == STATS_INODE
+            || matches!(
+                self.record_node(ino),
+                Some(RecordNode::Root | RecordNode::Stream(_))
+            )
+    }
+
+    /// Node in the record-files layout for the inode, if that layout is
+    /// used.
+    fn record_node(&self, ino: u64) -> Option<RecordNode> {
+        if self.layout == Layout::RecordFiles {
+            RecordNode::from_inode(ino)
+        } else {
+            None
+        }
+    }
+
+    /// Read a single record.
+    fn get_record(&mut self, seq: i64) -> Result<String, c_int> {
+        let row = self
+            .reader()
+            .0
+            .query_opt(
+                &format!(
+                    "SELECT {} AS line FROM {} WHERE c.seq = $1",
+                    CONTENT_LINE, CONTENT_JOIN
+                ),
+                &[&seq],
+            )
+            .map_err(|err| self.db_errno("read", seq as u64 | RECORD_FLAG, &err))?
+            .ok_or(ENOENT)?;
+        let mut line: String = row.get("line");
+        line.push(self.format.delimiter as char);
+        Ok(line)
+    }
+
+    /// Number of lines referring to the dictionary and number of
+    /// distinct lines in it.
+    fn dedup_ratio(&mut self) -> Result<(i64, i64), postgres::Error> {
+        let row = self.client.query_one(
+            "SELECT (SELECT count(*) FROM content WHERE dict_id IS NOT NULL), \
+                    (SELECT count(*) FROM dictionary)",
+            &[],
+        )?;
+        Ok((row.get(0), row.get(1)))
+    }
+
+    /// Number of lines stored for a file.
+    fn line_count(&mut self, ino: u64) -> Result<i64, c_int> {
+        self.get_inode(ino)?;
+        let ino = ino as i32;
+        let query = format!(
+            "SELECT {} FROM {} WHERE c.ino = $1",
+            line_count_expr(self.format),
+            CONTENT_JOIN
+        );
+        let row = self
+            .reader()
+            .0
+            .query_one(&query, &[&ino])
+            .map_err(|err| self.db_errno("getxattr", ino as u64, &err))?;
+        Ok(row.get(0))
+    }
+
+    /// Statistics for a file, which are the contents of its file in the
+    /// `.stats` directory.
+    ///
+    /// The write times are the times the lines were inserted, so with
+    /// asynchronous writes they are the times of the commits.
+    fn stats_contents(&mut self, ino: i32) -> Result<String, c_int> {
+        let attr = self.get_inode(ino as u64)?;
+        let query = format!(
+            "SELECT {} AS lines, min(c.written) AS first_write, \
+             max(c.written) AS last_write, string_agg(DISTINCT c.uid::text, ',') AS uids \
+             FROM {} WHERE c.ino = $1",
+            line_count_expr(self.format),
+            CONTENT_JOIN
+        );
+        let row = self
+            .reader()
+            .0
+            .query_one(&query, &[&ino])
+            .map_err(|err| self.db_errno("read", ino as u64 | STATS_FLAG, &err))?;
+        let time = |name| {
+            let time: Option<SystemTime> = row.get(name);
+            time.map_or("none".to_string(), |time| {
+                let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+                since_epoch.as_secs().to_string()
+            })
+        };
+        let lines: i64 = row.get("lines");
+        let uids: Option<String> = row.get("uids");
+        Ok(format!(
+            "lines: {}\nsize: {}\nfirst_write: {}\nlast_write: {}\nwriter_uids: {}\n",
+            lines,
+            attr.size,
+            time("first_write"),
+            time("last_write"),
+            uids.as_deref().unwrap_or("none"),
+        ))
+    }
+
+    /// Attributes of the `.stats` directory or a file in it, or `None`
+    /// if the inode is neither.
+    fn stats_attr(&mut self, ino: u64) -> Option<Result<FileAttr, c_int>> {
+        if ino == STATS_INODE {
+            return Some(Ok(read_only_dir_attr(STATS_INODE, 0, 0)));
+        }
+        let file = stats_file(ino)?;
+        Some(self.get_inode(file as u64).and_then(|file_attr| {
+            let contents = self.stats_contents(file)?;
+            let mut attr = new_attr(
+                ino as i64,
+                file_attr.uid,
+                file_attr.gid,
+                0o444,
+                self.io_block_size,
+            );
+            attr.size = contents.len() as u64;
+            attr.blocks = blocks(attr.size);
+            Ok(attr)
+        }))
+    }
+
+    /// Look up a file in the `.stats` directory.
+    fn lookup_stats(&mut self, name: &str) -> Result<FileAttr, c_int> {
+        let (attr, _) = self.lookup_name(name)?;
+        self.stats_attr(attr.ino | STATS_FLAG).unwrap()
+    }
+
+    /// Attributes of a node in the record-files layout.
+    fn record_attr(&mut self, node: RecordNode) -> Result<FileAttr, c_int> {
+        match node {
+            RecordNode::Root => {
+                // Each file has a directory with its records, and each
+                // of them has a link to this directory.
+                let files: i64 = self
+                    .client
+                    .query_one("SELECT count(*) FROM inodes WHERE ino <> 1", &[])
+                    .map_err(|err| self.db_errno("getattr", RECORDS_INODE, &err))?
+                    .get(0);
+                let mut attr = read_only_dir_attr(RECORDS_INODE, 0, 0);
+                attr.nlink += files as u32;
+                Ok(attr)
+            }
+            RecordNode::Stream(ino) => {
+                let attr = self.get_inode(ino as u64)?;
+                Ok(read_only_dir_attr(
+                    ino as u64 | STREAM_FLAG,
+                    attr.uid,
+                    attr.gid,
+                ))
+            }
+            RecordNode::Record(seq) => {
+                let record = self.get_record(seq)?;
+                let mut attr = new_attr(0, 0, 0, 0o444, self.io_block_size);
+                attr.ino = seq as u64 | RECORD_FLAG;
+                attr.size = record.len() as u64;
+                attr.blocks = blocks(attr.size);
+                Ok(attr)
+            }
+        }
+    }
+
+    /// Look up a name in a directory of the record-files layout.
+    fn lookup_record(&mut self, parent: RecordNode, name: &str) -> Result<FileAttr, c_int> {
+        match parent {
+            RecordNode::Root => {
+                let (attr, _) = self.lookup_name(name)?;
+                self.record_attr(RecordNode::Stream(attr.ino as i32))
+            }
+            RecordNode::Stream(ino) => {
+                let seq: i64 = name.parse().map_err(|_| ENOENT)?;
+                let row = self
+                    .client
+                    .query_opt(
+                        "SELECT seq FROM content WHERE ino = $1 AND seq = $2",
+                        &[&ino, &seq],
+                    )
+                    .map_err(|err| self.db_errno("lookup", ino as u64 | STREAM_FLAG, &err))?;
+                if row.is_none() {
+                    return Err(ENOENT);
+                }
+                self.record_attr(RecordNode::Record(seq))
+            }
+            RecordNode::Record(_) => Err(libc::ENOTDIR),
+        }
+    }
+
+    /// Entries of a directory in the record-files layout.
+    fn record_entries(
+        &mut self,
+        node: RecordNode,
+    ) -> Result<Vec<(String, u64, FileType)>, postgres::Error> {
+        match node {
+            RecordNode::Root => Ok(self
+                .scan_directory()?
+                .iter()
+                .map(|row| {
+                    let name: &str = row.get("name");
+                    let ino: i32 = row.get("ino");
+                    (
+                        name.to_string(),
+                        ino as u64 | STREAM_FLAG,
+                        FileType::Directory,
+                    )
+                })
+                .collect()),
+            RecordNode::Stream(ino) => Ok(self
+                .reader()
+                .0
+                .query(
+                    "SELECT seq FROM content WHERE ino = $1 ORDER BY seq",
+                    &[&ino],
+                )?
+                .iter()
+                .map(|row| {
+                    let seq: i64 = row.get("seq");
+                    (
+                        seq.to_string(),
+                        seq as u64 | RECORD_FLAG,
+                        FileType::RegularFile,
+                    )
+                })
+                .collect()),
+            RecordNode::Record(_) => Ok(Vec::new()),
+        }
+    }
+
+    /// Contents of a virtual file, or `None` if the inode is not a
+    /// virtual file.
+    fn virtual_contents(&self, ino: u64) -> Option<String> {
+        match ino {
+            STATUS_INODE => Some(self.status.report()),
+            ERRORS_INODE if self.status.max_errors > 0 => Some(self.status.errors_report()),
+            HANDLES_INODE => {
+                let mut handles: Vec<_> = self.handles.iter().collect();
+                handles.sort_by_key(|(fh, _)| **fh);
+                Some(
+                    handles
+                        .into_iter()
+                        .map(|(fh, handle)| handle.report(*fh))
+                        .collect(),
+                )
+            }
+            _ => None,
+        }
+    }
+
+    /// Attributes of a virtual file, or `None` if the inode is not a
+    /// virtual file.
+    fn virtual_attr(&self, ino: u64) -> Option<FileAttr> {
+        let contents = self.virtual_contents(ino)?;
+        let mut attr = new_attr(ino as i64, 0, 0, 0o444, self.io_block_size);
+        attr.size = contents.len() as u64;
+        attr.blocks = blocks(attr.size);
+        Some(attr)
+    }
+
+    /// Error for a parent directory other than the root directory.
+    ///
+    /// Files can only be created in the root directory. The directories
+    /// of the record-files layout are read-only, and any other inode is
+    /// either not a directory or does not exist. Errors looking up the
+    /// inode are returned as they are, so that a failing database is
+    /// not reported as a missing directory.
+    fn parent_errno(&mut self, parent: u64) -> c_int {
+        if self.record_node(parent).is_some() || parent == STATS_INODE {
+            libc::EACCES
+        } else if DatabaseFS::is_virtual(parent) {
+            libc::ENOTDIR
+        } else {
+            match self.get_inode(parent) {
+                Ok(_) => libc::ENOTDIR,
+                Err(errno) => errno,
+            }
+        }
+    }
+
+    /// Check that another file can be opened without exceeding the
+    /// limit on open files.
+    fn check_open_files(&self) -> Result<(), c_int> {
+        match self.max_open_files {
+            Some(max) if self.handles.len() >= max => {
+                warn!("refusing to open more than {} files", max);
+                Err(libc::ENFILE)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Check if a write should be attempted, which it is unless the
+    /// database has been found to be unwritable. Even then, a write is
+    /// attempted now and then to find out when the database can be
+    /// written again.
+    fn check_writable(&mut self) -> Result<(), c_int> {
+        match self.degraded {
+            Some(attempt) if attempt.elapsed() < WRITE_PROBE_INTERVAL => Err(libc::EROFS),
+            Some(_) => {
+                self.degraded = Some(Instant::now());
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Track whether the database can be written from the result of a
+    /// write, switching to rejecting writes with `EROFS` if the
+    /// database is read-only or full and back again once a write
+    /// succeeds.
+    fn check_write<T>(&mut self, result: Result<T, c_int>) -> Result<T, c_int> {
+        match result {
+            Ok(value) => {
+                if self.degraded.take().is_some() {
+                    info!("database is writable again, accepting writes");
+                }
+                Ok(value)
+            }
+            Err(errno @ (libc::EROFS | libc::ENOSPC)) if self.degrade_to_readonly => {
+                if self.degraded.is_none() {
+                    warn!(
+                        "database is not writable ({}), rejecting writes",
+                        std::io::Error::from_raw_os_error(errno)
+                    );
+                }
+                self.degraded = Some(Instant::now());
+                Err(libc::EROFS)
+            }
+            Err(errno) => Err(errno),
+        }
+    }
+
+    /// Allocate a file handle for an open file.
+    fn open_handle(&mut self, ino: u64, flags: i32) -> u64 {
+        self.last_fh += 1;
+        self.handles.insert(
+            self.last_fh,
+            Handle {
+                ino,
+                flags,
+                pending: Vec::new(),
+            },
+        );
+        self.last_fh
+    }
+
+    /// Attributes of an inode from a row of the inodes table.
+    fn row_attr(&self, row: &Row) -> FileAttr {
+        let ino: i32 = row.get("ino");
+        let uid: i32 = row.get("uid");
+        let gid: i32 = row.get("gid");
+        let mode: i32 = row.get("mode");
+        let size: i64 = row.get("size");
+        let mtime: SystemTime = row.get("mtime");
+        let ctime: Option<SystemTime> = row.get("ctime");
+        let crtime: Option<SystemTime> = row.get("crtime");
+        let mut attr = FileAttr {
+            size: size as u64,
+            blocks: blocks(size as u64),
+            mtime,
+            ctime: ctime.unwrap_or(mtime),
+            crtime: crtime.unwrap_or(UNIX_EPOCH),
+            ..new_attr(
+                ino as i64,
+                uid as u32,
+                gid as u32,
+                mode as u32,
+                self.io_block_size,
+            )
+        };
+        // A directory has a link from each subdirectory, and the only
+        // subdirectories of the root are the `.records` directory and
+        // the `.stats` directory when it is listed.
+        if ino as u64 == FUSE_ROOT_ID && self.layout == Layout::RecordFiles {
+            attr.nlink += 1;
+        }
+        if ino as u64 == FUSE_ROOT_ID && self.show_stats {
+            attr.nlink += 1;
+        }
+        attr
+    }
+
+    /// Look up a file by name, returning its attributes and generation.
+    fn lookup_name(&mut self, name: &str) -> Result<(FileAttr, u64), c_int> {
+        let row = self
+            .read_opt(|statements| &statements.name_lookup, &[&name])
+            .map_err(|err| self.db_errno("lookup", FUSE_ROOT_ID, &err))?
+            .filter(|row| self.is_visible(row.get("ino")))
+            .ok_or(ENOENT)?;
+        let attr = self.row_attr(&row);
+        let generation: i64 = row.get("generation");
+        debug!("found name {:?}: {:?}", name, attr);
+        Ok((attr, generation as u64))
+    }
+
+    fn get_inode(&mut self, ino: u64) -> Result<FileAttr, c_int> {
+        self.inode_entry(ino).map(|(attr, _)| attr)
+    }
+
+    /// Look up an inode, returning its attributes and generation.
+    fn inode_entry(&mut self, ino: u64) -> Result<(FileAttr, u64), c_int> {
+        let ino = ino as i32;
+        let row = self
+            .read_opt(|statements| &statements.inode_lookup, &[&ino])
+            .map_err(|err| self.db_errno("getattr", ino as u64, &err))?
+            .ok_or(ENOENT)?;
+        let attr = self.row_attr(&row);
+        let generation: i64 = row.get("generation");
+        debug!("found inode {}: {:?}", ino, attr);
+        Ok((attr, generation as u64))
+    }
+
+    /// Look up "." in any inode or ".." in a directory.
+    ///
+    /// With export support, the kernel looks up "." to find the inode
+    /// of an NFS file handle, which is refused as stale unless the
+    /// generation matches, and ".." to find the parent of a directory.
+    /// Inodes other than the files have generation 0, since their
+    /// numbers never refer to anything else.
+    fn lookup_dot(&mut self, ino: u64, dotdot: bool) -> Result<(FileAttr, u64), c_int> {
+        let ino = if !dotdot {
+            ino
+        } else {
+            match self.record_node(ino) {
+                Some(RecordNode::Root) => FUSE_ROOT_ID,
+                Some(RecordNode::Stream(_)) => RECORDS_INODE,
+                _ if ino == FUSE_ROOT_ID || ino == STATS_INODE => FUSE_ROOT_ID,
+                _ => return Err(libc::ENOTDIR),
+            }
+        };
+        if ino == LATEST_INODE {
+            self.latest_attr().map(|attr| (attr, 0)).ok_or(ENOENT)
+        } else if let Some(attr) = self.virtual_attr(ino) {
+            Ok((attr, 0))
+        } else if let Some(node) = self.record_node(ino) {
+            self.record_attr(node).map(|attr| (attr, 0))
+        } else if let Some(result) = self.stats_attr(ino) {
+            result.map(|attr| (attr, 0))
+        } else {
+            self.inode_entry(ino)
+        }
+    }
+
+    fn allocate_inode(
+        &mut self,
+        name: &str,
+        mode: u32,
+        uid: u32,
+        gid: u32,
+    ) -> Result<(FileAttr, u64), postgres::Error> {
+        let row = {
+            let mode = mode as i32;
+            let uid = uid as i32;
+            let gid = gid as i32;
+            with_retry(|| {
+                self.client
+                    .query_one(&self.inode_insert, &[&name, &mode, &uid, &gid])
+            })?
+        };
+        let ino: i32 = row.get("ino");
+        let crtime: SystemTime = row.get("crtime");
+        let generation: i64 = row.get("generation");
+        let attr = FileAttr {
+            crtime,
+            ..new_attr(ino as i64, uid, gid, mode, self.io_block_size)
+        };
+        Ok((attr, generation as u64))
+    }
+
+    /// Create a file, or open an existing file unless `O_EXCL` is
+    /// given, returning its attributes and generation.
+    fn create_file(
+        &mut self,
+        name: &str,
+        mode: u32,
+        uid: u32,
+        gid: u32,
+        flags: i32,
+    ) -> Result<(FileAttr, u64), c_int> {
+        if RESERVED_NAMES.contains(&name) {
+            return Err(libc::EEXIST);
+        }
+        match self.lookup_name(name) {
+            // Without O_EXCL, creating an existing file just opens it.
+            Ok(_) if flags & libc::O_EXCL != 0 => Err(libc::EEXIST),
+            Ok((attrs, generation)) if flags & libc::O_TRUNC != 0 => {
+                let attrs = self.truncate_inode("create", attrs.ino)?;
+                Ok((attrs, generation))
+            }
+            Ok(entry) => Ok(entry),
+            Err(ENOENT) => {
+                let mode = if mode & 0o7777 == 0 {
+                    mode | self.default_file_mode
+                } else {
+                    clear_special_bits(mode)
+                };
+                let entry = self
+                    .allocate_inode(name, mode, uid, gid)
+                    .map_err(|err| self.db_errno("create", FUSE_ROOT_ID, &err))?;
+                with_retry(|| {
+                    self.client
+                        .execute(MTIME_UPDATE, &[&(FUSE_ROOT_ID as i32), &0i64])
+                })
+                .map_err(|err| self.db_errno("create", FUSE_ROOT_ID, &err))?;
+                self.latest = Some(name.to_string());
+                Ok(entry)
+            }
+            Err(errno) => Err(errno),
+        }
+    }
+
+    /// Discard the contents of a file, returning its new attributes.
+    /// The contents and the size are changed in the same transaction,
+    /// so they always agree.
+    fn truncate_inode(&mut self, op: &str, ino: u64) -> Result<FileAttr, c_int> {
+        // Queued writes were made before the truncation, so they have
+        // to be stored before the contents are discarded.
+        if let Some(Err(errno)) = self.writer.as_ref().map(AsyncWriter::flush) {
+            return Err(errno);
+        }
+        let ino = ino as i32;
+        let row = with_retry(|| {
+            let mut txn = self.client.transaction()?;
+            txn.execute("DELETE FROM content WHERE ino = $1", &[&ino])?;
+            let row = txn.query_one(
+                "UPDATE inodes SET size = 0, mtime = now(), ctime = now() \
+                 WHERE ino = $1 \
+                 RETURNING ino, mode, uid, gid, size, mtime, ctime, crtime",
+                &[&ino],
+            )?;
+            txn.commit()?;
+            Ok(row)
+        })
+        .map_err(|err| self.db_errno(op, ino as u64, &err))?;
+        Ok(self.row_attr(&row))
+    }
+
+    /// Apply the operations in a log written with `--record` to the
+    /// database, without mounting the file system.
+    ///
+    /// Only operations that modify the file system are applied. The
+    /// inodes in the log are used as is, so the log has to be replayed
+    /// against tables in the same state as when it was recorded, which
+    /// is normally new tables.
+    fn replay(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let log = BufReader::new(File::open(path)?);
+        for (number, line) in log.lines().enumerate() {
+            let result = self
+                .replay_entry(&line?)
+                .map_err(|err| format!("line {}: {}", number + 1, err))?;
+            if let Err(errno) = result {
+                warn!("line {}: operation failed with errno {}", number + 1, errno);
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply a single operation from a log written with `--record`,
+    /// returning the result of the operation if the entry is valid.
+    fn replay_entry(&mut self, line: &str) -> Result<Result<(), c_int>, String> {
+        let entry = recorder::parse_entry(line)?;
+        let result = match entry.op()? {
+            "create" => match entry.str("name")?.to_str() {
+                Some(name) => self
+                    .create_file(
+                        name,
+                        entry.num("mode")?,
+                        entry.num("uid")?,
+                        entry.num("gid")?,
+                        entry.num("flags")?,
+                    )
+                    .map(|_| ()),
+                None => Err(libc::EINVAL),
+            },
+            "write" => {
+                // Logs recorded before the writer was included
+                // have no writer fields.
+                let writer = Writer {
+                    uid: entry.opt("uid")?.unwrap_or(0),
+                    pid: entry.opt("pid")?.unwrap_or(0),
+                };
+                self.write_inode(
+                    entry.num("ino")?,
+                    entry.num("offset")?,
+                    &entry.bytes("data")?,
+                    &writer,
+                )
+            }
+            "copy_file_range" => self
+                .copy_inode(
+                    entry.num("ino_in")?,
+                    entry.num("offset_in")?,
+                    entry.num("ino_out")?,
+                    entry.num("offset_out")?,
+                    entry.num("len")?,
+                )
+                .map(|_| ()),
+            op => {
+                debug!("skipping {}", op);
+                Ok(())
+            }
+        };
+        Ok(result)
+    }
+
+    /// Import the regular files in a directory, each as a file with the
+    /// same name, mode, and owner, with its contents written as a
+    /// single write.
+    ///
+    /// This is the inverse of `export`. Files that already exist are
+    /// skipped, so an interrupted import can be run again, as are files
+    /// that cannot be stored because of their name or contents.
+    fn import(&mut self, dir: &Path) -> Result<(), Box<dyn Error>> {
+        let mut count = 0;
+        for (path, metadata) in import::files(dir)? {
+            let name = match path.file_name().unwrap().to_str() {
+                Some(name) => name,
+                None => {
+                    warn!("skipping {}: name is not UTF-8", path.display());
+                    continue;
+                }
+            };
+            let data = fs::read(&path)?;
+            if let Err(err) = from_utf8(&data) {
+                warn!("skipping {}: {}", path.display(), err);
+                continue;
+            }
+            let (uid, gid) = (metadata.uid(), metadata.gid());
+            let ino = match self.create_file(name, metadata.mode(), uid, gid, libc::O_EXCL) {
+                Ok((attr, _)) => attr.ino as i32,
+                Err(libc::EEXIST) => {
+                    warn!("skipping {}: file exists", path.display());
+                    continue;
+                }
+                Err(errno) => {
+                    return Err(format!("creating {:?} failed: errno {}", name, errno).into())
+                }
+            };
+            if let Err(errno) = self.write_inode(ino, 0, &data, &Writer { uid, pid: 0 }) {
+                return Err(format!("writing {:?} failed: errno {}", name, errno).into());
+            }
+            debug!("imported {} into inode {}", path.display(), ino);
+            count += 1;
+        }
+        info!("imported {} files from {}", count, dir.display());
+        Ok(())
     }
-}
 
-impl DatabaseFS {
-    fn new(params: String) -> Result<DatabaseFS, postgres::Error> {
-        let mut client = Client::connect(&params, NoTls)?;
-        client.execute(
-            "CREATE TABLE inodes (ino serial, name name, mode int, uid int, gid int)",
-            &[],
-        )?;
-        client.execute(
-            "ALTER SEQUENCE inodes_ino_seq MINVALUE 10 START 10 RESTART",
-            &[],
-        )?;
-        client.execute("CREATE TABLE content (ino int, line text)", &[])?;
-
-        let entries = None;
-        let name_lookup =
-            client.prepare("SELECT ino, uid, gid, mode FROM inodes WHERE name = $1")?;
-        let inode_lookup =
-            client.prepare("SELECT ino, uid, gid, mode FROM inodes WHERE ino = $1")?;
-        let content_insert = client.prepare("INSERT INTO content(ino, line) VALUES ($1,$2)")?;
-        let inode_insert = client.prepare(
-            "INSERT INTO inodes(name, mode, uid, gid) VALUES ($1, $2, $3, $4) RETURNING ino",
+    /// Seed the file system with `files` files containing `lines`
+    /// lines each.
+    ///
+    /// This bypasses the file system and inserts all files in a single
+    /// transaction, so it is mostly useful for creating data sets for
+    /// benchmarks. The lines are stored the same way as written lines.
+    /// Files that already exist, for example from seeding before, are
+    /// left as they are.
+    fn seed(&mut self, files: u32, lines: u32) -> Result<(), Box<dyn Error>> {
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let mut txn = self.client.transaction()?;
+        let inodes = txn.query(
+            "INSERT INTO inodes(name, mode, uid, gid) \
+             SELECT 'seed-' || n, $2, $3, $4 FROM generate_series(0, $1 - 1) AS n \
+             WHERE NOT EXISTS (SELECT FROM inodes WHERE name = 'seed-' || n) \
+             RETURNING ino",
+            &[&(files as i32), &0o644i32, &(uid as i32), &(gid as i32)],
         )?;
-        let directory_scan = client.prepare("SELECT name, ino FROM inodes ORDER BY ino")?;
-
-        Ok(DatabaseFS {
-            client,
-            entries,
-            name_lookup,
-            content_insert,
-            inode_lookup,
-            inode_insert,
-            directory_scan,
-        })
+        txn.execute(MTIME_UPDATE, &[&(FUSE_ROOT_ID as i32), &0i64])?;
+        let added = inodes.len();
+        let mut count = 0;
+        for row in inodes {
+            let ino: i32 = row.get("ino");
+            let data: Vec<_> = (0..lines)
+                .map(|line| format!("line {} of inode {}", line, ino))
+                .collect();
+            let mut end = 0;
+            let lines: Vec<_> = data
+                .iter()
+                .map(|line| {
+                    let pos = end;
+                    end += line.len() as i64 + 1;
+                    (pos, line.as_str())
+                })
+                .collect();
+            count += insert_lines(
+                &mut txn,
+                &self.content_insert,
+                self.idempotent_writes,
+                ino,
+                end,
+                None,
+                &lines,
+            )?;
+        }
+        txn.commit()?;
+        info!("seeded {} files with {} lines in total", added, count);
+        Ok(())
     }
 
-    fn lookup_name(&mut self, name: &str) -> Result<FileAttr, postgres::Error> {
-        let row = self.client.query_one(&self.name_lookup, &[&name])?;
-        let ino: i32 = row.get("ino");
-        let uid: i32 = row.get("uid");
-        let gid: i32 = row.get("gid");
-        let mode: i32 = row.get("mode");
-        let attr = new_attr(ino as i64, uid as u32, gid as u32, mode as u32);
-        debug!("found name {:?}: {:?}", name, attr);
-        Ok(attr)
+    /// Writer to store with the lines of a write, if any.
+    fn annotation(&self, writer: &Writer) -> Option<Writer> {
+        if self.annotate_writer {
+            Some(*writer)
+        } else {
+            None
+        }
     }
 
-    fn get_inode(&mut self, ino: u64) -> Result<FileAttr, c_int> {
-        let ino = ino as i32;
-        let result = self.client.query_one(&self.inode_lookup, &[&ino]);
-        let row = match result {
-            Ok(row) => row,
-            Err(err) => {
-                debug!("query error: {}", err);
-                return Err(libc::ENOENT);
+    /// Insert the writes kept for a handle in a single transaction,
+    /// so that either all or none of them are stored.
+    ///
+    /// The writes are dropped also if the insert fails, since the
+    /// error is reported to the process closing the file.
+    fn flush_handle(&mut self, fh: u64) -> Result<(), c_int> {
+        let (ino, pending) = match self.handles.get_mut(&fh) {
+            Some(handle) if !handle.pending.is_empty() => {
+                (handle.ino, std::mem::take(&mut handle.pending))
             }
+            _ => return Ok(()),
         };
-        let ino: i32 = row.get("ino");
-        let uid: i32 = row.get("uid");
-        let gid: i32 = row.get("gid");
-        let mode: i32 = row.get("mode");
-        let attr = new_attr(ino as i64, uid as u32, gid as u32, mode as u32);
-        debug!("found inode {}: {:?}", ino, attr);
-        Ok(attr)
+        self.check_writable()?;
+        let result = with_retry(|| {
+            let mut txn = self.client.transaction()?;
+            if self.sync_writes {
+                txn.batch_execute("SET LOCAL synchronous_commit = on")?;
+            }
+            let mut rows = 0;
+            for (offset, data, writer) in &pending {
+                // The data was checked to be valid when it was written.
+                let lines = split_lines(*offset, data, self.format).unwrap();
+                rows += insert_lines(
+                    &mut txn,
+                    &self.content_insert,
+                    self.idempotent_writes,
+                    ino as i32,
+                    offset + data.len() as i64,
+                    *writer,
+                    &lines,
+                )?;
+            }
+            txn.commit()?;
+            Ok(rows)
+        })
+        .map_err(|err| {
+            self.status.record_error(&err);
+            self.db_errno("flush", ino, &err)
+        });
+        let rows = self.check_write(result)?;
+        self.status
+            .rows_committed
+            .fetch_add(rows, Ordering::Relaxed);
+        Ok(())
     }
 
-    fn allocate_inode(
+    /// Copy the lines of one file to the end of another file inside
+    /// the database, returning the number of bytes copied.
+    ///
+    /// Files are stored as lines rather than bytes, so only copying a
+    /// whole file to the end of another file is supported. Other
+    /// ranges fail with `EOPNOTSUPP`, which makes the kernel fall back
+    /// to reading and writing the data.
+    fn copy_inode(
         &mut self,
-        name: &str,
-        mode: u32,
-        uid: u32,
-        gid: u32,
-    ) -> Result<FileAttr, postgres::Error> {
-        let ino: i32 = {
-            let mode = mode as i32;
-            let uid = uid as i32;
-            let gid = gid as i32;
-            let row = self
-                .client
-                .query_one(&self.inode_insert, &[&name, &mode, &uid, &gid])?;
-            row.get("ino")
-        };
-        Ok(new_attr(ino as i64, uid, gid, mode))
+        ino_in: i32,
+        offset_in: i64,
+        ino_out: i32,
+        offset_out: i64,
+        len: u64,
+    ) -> Result<u64, c_int> {
+        let max_file_size = self.max_file_size;
+        let result = with_retry(|| {
+            let mut txn = self.client.transaction()?;
+            let rows = txn.query(
+                "SELECT ino, size FROM inodes WHERE ino = ANY($1)",
+                &[&vec![ino_in, ino_out]],
+            )?;
+            let size_of = |ino: i32| {
+                rows.iter()
+                    .find(|row| row.get::<_, i32>("ino") == ino)
+                    .map(|row| row.get::<_, i64>("size"))
+            };
+            let (size, dest_size) = match (size_of(ino_in), size_of(ino_out)) {
+                (Some(size), Some(dest_size)) => (size, dest_size),
+                _ => return Ok(Err(libc::EBADF)),
+            };
+            if offset_in != 0
+                || len < size as u64
+                || offset_out != dest_size
+                || size > u32::MAX as i64
+            {
+                return Ok(Err(libc::EOPNOTSUPP));
+            }
+            if matches!(max_file_size, Some(max) if (offset_out + size) as u64 > max) {
+                return Ok(Err(libc::EFBIG));
+            }
+            // Positions are byte offsets from the start of the file
+            // they were written to, so the copied lines are at the
+            // same offsets from the end of the destination. Lines
+            // stored without a position are copied without one.
+            let rows = txn.execute(
+                "INSERT INTO content(ino, line, dict_id, pos, uid, pid) \
+                 SELECT $2, line, dict_id, CASE WHEN $4 THEN pos + $3 END, uid, pid \
+                 FROM content WHERE ino = $1 ORDER BY seq \
+                 ON CONFLICT (ino, pos) DO NOTHING",
+                &[&ino_in, &ino_out, &offset_out, &self.idempotent_writes],
+            )?;
+            txn.execute(MTIME_UPDATE, &[&ino_out, &(offset_out + size)])?;
+            txn.commit()?;
+            Ok(Ok((size, rows)))
+        });
+        let (size, rows) =
+            result.map_err(|err| self.db_errno("copy_file_range", ino_out as u64, &err))??;
+        self.status
+            .rows_committed
+            .fetch_add(rows, Ordering::Relaxed);
+        Ok(size as u64)
     }
 
     // Data is split up into lines and written to the content table.
-    fn write_inode(&mut self, ino: i32, data: &[u8]) -> Result<(), postgres::Error> {
-        let ino = ino as i32;
-        let lines: Result<Vec<_>, Utf8Error> = data
-            .split(|&b| b == b'\n')
-            .filter_map(|c| {
-                if c.len() > 0 {
-                    Some(from_utf8(c))
-                } else {
-                    None
-                }
+    //
+    // With synchronous writes, all lines of the write are inserted in
+    // a single transaction that is committed with synchronous commit
+    // regardless of the server configuration, so the data is on disk
+    // once this returns. Otherwise each line is committed on its own
+    // using the server defaults.
+    //
+    // Transient errors are retried, for the transaction as a whole or
+    // for the failing statement when each statement commits on its own.
+    //
+    // The writer is stored with each line when writers are annotated.
+    //
+    // Lines are stored as text, so data that is not valid UTF-8 fails
+    // with EINVAL without storing anything.
+    fn write_inode(
+        &mut self,
+        ino: i32,
+        offset: i64,
+        data: &[u8],
+        writer: &Writer,
+    ) -> Result<(), c_int> {
+        let writer = self.annotation(writer);
+        let lines = split_lines(offset, data, self.format).map_err(|_| libc::EINVAL)?;
+        let end = offset + data.len() as i64;
+        let rows = self.insert_write(ino, end, writer, &lines).map_err(|err| {
+            self.status.record_error(&err);
+            self.db_errno("write", ino as u64, &err)
+        })?;
+        self.status
+            .rows_committed
+            .fetch_add(rows, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Insert the lines of a write ending at `end`, returning the
+    /// number of rows inserted.
+    fn insert_write(
+        &mut self,
+        ino: i32,
+        end: i64,
+        writer: Option<Writer>,
+        lines: &[(i64, &str)],
+    ) -> Result<u64, postgres::Error> {
+        if self.sync_writes {
+            with_retry(|| {
+                let mut txn = self.client.transaction()?;
+                txn.batch_execute("SET LOCAL synchronous_commit = on")?;
+                let rows = insert_lines(
+                    &mut txn,
+                    &self.content_insert,
+                    self.idempotent_writes,
+                    ino,
+                    end,
+                    writer,
+                    lines,
+                )?;
+                txn.commit()?;
+                Ok(rows)
             })
-            .collect();
-        for line in lines.unwrap() {
-            self.client.execute(&self.content_insert, &[&ino, &line])?;
+        } else {
+            let (uid, pid) = Writer::columns(writer);
+            let mut rows = 0;
+            for (pos, line) in lines {
+                let pos = line_position(self.idempotent_writes, *pos);
+                rows += with_retry(|| {
+                    self.client
+                        .execute(&self.content_insert, &[&ino, line, &pos, &uid, &pid])
+                })?;
+            }
+            with_retry(|| self.client.execute(MTIME_UPDATE, &[&ino, &end]))?;
+            Ok(rows)
         }
-        Ok(())
     }
 }
 
 impl Filesystem for DatabaseFS {
-    fn init(&mut self, _req: &Request, _config: &mut KernelConfig) -> Result<(), c_int> {
+    fn init(&mut self, _req: &Request, config: &mut KernelConfig) -> Result<(), c_int> {
+        info!("Using FUSE protocol version {}", FUSE_PROTOCOL_VERSION);
+        if let Err(nearest) = config.set_max_write(self.max_write) {
+            warn!(
+                "max write size {} not supported, using {}",
+                self.max_write, nearest
+            );
+            config.set_max_write(nearest).unwrap();
+        }
+        if let Some(max_readahead) = self.max_readahead {
+            if let Err(nearest) = config.set_max_readahead(max_readahead) {
+                warn!(
+                    "max readahead {} not supported, using {}",
+                    max_readahead, nearest
+                );
+                config.set_max_readahead(nearest).unwrap();
+            }
+        }
+        let mut capabilities = FUSE_BIG_WRITES;
+        if self.export_support {
+            capabilities |= FUSE_EXPORT_SUPPORT;
+        }
+        if let Err(unsupported) = config.add_capabilities(capabilities) {
+            warn!("kernel does not support capabilities {:#x}", unsupported);
+        }
         Ok(())
     }
 
+    fn destroy(&mut self) {
+        // Wait for the queued writes so that they are counted.
+        self.writer = None;
+        if self.dedup {
+            match self.dedup_ratio() {
+                Ok((lines, distinct)) => info!(
+                    "{} lines stored as {} distinct lines, dedup ratio {:.2}",
+                    lines,
+                    distinct,
+                    lines as f64 / distinct.max(1) as f64
+                ),
+                Err(err) => warn!("computing dedup ratio failed: {}", err),
+            }
+        }
+    }
+
     /// Look up the name and return the attributes.
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        self.status.touch();
+        debug!(
+            "lookup() called with req={} parent={} name={:?}",
+            req.unique(),
+            parent,
+            name
+        );
         if name.len() > MAX_NAME_LENGTH as usize {
             reply.error(libc::ENAMETOOLONG);
             return;
         }
 
-        if parent == FUSE_ROOT_ID {
-            if let Ok(attrs) = self.lookup_name(name.to_str().unwrap()) {
-                reply.entry(&ZERO, &attrs, 0);
+        if name == "." || name == ".." {
+            match self.lookup_dot(parent, name == "..") {
+                Ok((attrs, generation)) => reply.entry(&ZERO, &attrs, generation),
+                Err(errno) => reply.error(errno),
+            }
+        } else if let Some(node) = self.record_node(parent) {
+            match self.lookup_record(node, name.to_str().unwrap()) {
+                Ok(attrs) => reply.entry(&ZERO, &attrs, 0),
+                Err(errno) => reply.error(errno),
+            }
+        } else if parent == STATS_INODE {
+            match self.lookup_stats(name.to_str().unwrap()) {
+                Ok(attrs) => reply.entry(&ZERO, &attrs, 0),
+                Err(errno) => reply.error(errno),
+            }
+        } else if parent == FUSE_ROOT_ID && name == STATS_NAME {
+            reply.entry(&ZERO, &read_only_dir_attr(STATS_INODE, 0, 0), 0);
+        } else if parent == FUSE_ROOT_ID
+            && name == RECORDS_NAME
+            && self.layout == Layout::RecordFiles
+        {
+            reply.entry(&ZERO, &read_only_dir_attr(RECORDS_INODE, 0, 0), 0);
+        } else if parent == FUSE_ROOT_ID && name == LATEST_NAME {
+            match self.latest_attr() {
+                Some(attrs) => reply.entry(&ZERO, &attrs, 0),
+                None => reply.error(ENOENT),
+            }
+        } else if parent == FUSE_ROOT_ID {
+            if let Some(ino) = self.virtual_inode(name) {
+                reply.entry(&ZERO, &self.virtual_attr(ino).unwrap(), 0);
             } else {
-                reply.error(libc::ENOENT);
+                match self.lookup_name(name.to_str().unwrap()) {
+                    Ok((attrs, generation)) => reply.entry(&ZERO, &attrs, generation),
+                    Err(errno) => reply.error(errno),
+                }
             }
         } else {
-            reply.error(libc::EBADF);
+            reply.error(self.parent_errno(parent));
         }
     }
 
     fn forget(&mut self, _req: &Request, _inode: u64, _nlookup: u64) {}
 
-    fn getattr(&mut self, _req: &Request, inode: u64, reply: ReplyAttr) {
-        if inode == FUSE_ROOT_ID {
-            reply.attr(&ZERO, &CAPTURE_DIR_ATTR);
-        } else if let Ok(attrs) = self.get_inode(inode) {
+    fn readlink(&mut self, req: &Request, inode: u64, reply: ReplyData) {
+        self.status.touch();
+        debug!("readlink() called with req={} ino={}", req.unique(), inode);
+        match &self.latest {
+            Some(target) if inode == LATEST_INODE => reply.data(target.as_bytes()),
+            _ => reply.error(libc::EINVAL),
+        }
+    }
+
+    fn getattr(&mut self, req: &Request, inode: u64, reply: ReplyAttr) {
+        self.status.touch();
+        debug!("getattr() called with req={} ino={}", req.unique(), inode);
+        if inode == LATEST_INODE {
+            match self.latest_attr() {
+                Some(attrs) => reply.attr(&ZERO, &attrs),
+                None => reply.error(ENOENT),
+            }
+        } else if let Some(attrs) = self.virtual_attr(inode) {
             reply.attr(&ZERO, &attrs);
+        } else if let Some(node) = self.record_node(inode) {
+            match self.record_attr(node) {
+                Ok(attrs) => reply.attr(&ZERO, &attrs),
+                Err(errno) => reply.error(errno),
+            }
+        } else if let Some(result) = self.stats_attr(inode) {
+            match result {
+                Ok(attrs) => reply.attr(&ZERO, &attrs),
+                Err(errno) => reply.error(errno),
+            }
         } else {
-            reply.error(ENOENT);
+            match self.get_inode(inode) {
+                Ok(attrs) => reply.attr(&ZERO, &attrs),
+                Err(errno) => reply.error(errno),
+            }
+        }
+    }
+
+    /// Get an extended attribute. The only one is the number of lines
+    /// of a regular file, which is computed when asked for.
+    fn getxattr(&mut self, req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        self.status.touch();
+        debug!(
+            "getxattr() called with req={} ino={} name={:?}",
+            req.unique(),
+            ino,
+            name
+        );
+        if name != LINE_COUNT_XATTR || self.is_directory(ino) || DatabaseFS::is_virtual(ino) {
+            reply.error(libc::ENODATA);
+            return;
+        }
+        match self.line_count(ino) {
+            Ok(count) => reply_xattr(count.to_string().as_bytes(), size, reply),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn listxattr(&mut self, req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        self.status.touch();
+        debug!("listxattr() called with req={} ino={}", req.unique(), ino);
+        if self.is_directory(ino) || DatabaseFS::is_virtual(ino) {
+            reply_xattr(&[], size, reply);
+            return;
+        }
+        match self.get_inode(ino) {
+            Ok(_) => reply_xattr(format!("{}\0", LINE_COUNT_XATTR).as_bytes(), size, reply),
+            Err(errno) => reply.error(errno),
         }
     }
 
     fn setattr(
         &mut self,
-        _req: &Request,
+        req: &Request,
         inode: u64,
         mode: Option<u32>,
         uid: Option<u32>,
@@ -328,6 +3903,7 @@ impl Filesystem for DatabaseFS {
         _flags: Option<u32>,
         reply: ReplyAttr,
     ) {
+        self.status.touch();
         let mut attrs = match self.get_inode(inode) {
             Ok(attrs) => attrs,
             Err(error_code) => {
@@ -338,55 +3914,101 @@ impl Filesystem for DatabaseFS {
 
         // This is chmod()
         if let Some(mode) = mode {
-            debug!("setting mode: ino={} mode={:?}", inode, mode);
-            // TODO: Check permission
-            attrs.perm = mode as u16;
-            let result = self.client.execute(
-                "UPDATE inodes SET mode = $1 WHERE ino = $2",
-                &[&mode, &(inode as i32)],
+            debug!(
+                "setting mode: req={} ino={} mode={:?}",
+                req.unique(),
+                inode,
+                mode
             );
-            if let Err(_) = result {
-                reply.error(libc::EINVAL);
+            // TODO: Check permission
+            let mode = clear_special_bits(mode);
+            attrs.perm = (mode & 0o7777) as u16;
+            let result = with_retry(|| {
+                self.client.execute(
+                    "UPDATE inodes SET mode = $1, ctime = now() WHERE ino = $2",
+                    &[&(mode as i32), &(inode as i32)],
+                )
+            });
+            if let Err(err) = result {
+                reply.error(self.db_errno("setattr", inode, &err));
                 return;
             }
         }
 
         // This is chown()
         if let Some(gid) = gid {
-            debug!("setting gid: ino={} gid={:?}", inode, gid);
-            attrs.gid = gid;
-            let result = self.client.execute(
-                "UPDATE inodes SET gid = $1 WHERE ino = $2",
-                &[&gid, &(inode as i32)],
+            debug!(
+                "setting gid: req={} ino={} gid={:?}",
+                req.unique(),
+                inode,
+                gid
             );
-            if let Err(_) = result {
-                reply.error(libc::EINVAL);
+            attrs.gid = gid;
+            let result = with_retry(|| {
+                self.client.execute(
+                    "UPDATE inodes SET gid = $1, ctime = now() WHERE ino = $2",
+                    &[&(gid as i32), &(inode as i32)],
+                )
+            });
+            if let Err(err) = result {
+                reply.error(self.db_errno("setattr", inode, &err));
                 return;
             }
         }
 
         if let Some(uid) = uid {
-            debug!("setting uid: ino={} uid={:?}", inode, uid);
-            attrs.uid = uid;
-            let result = self.client.execute(
-                "UPDATE inodes SET uid = $1 WHERE ino = $2",
-                &[&uid, &(inode as i32)],
+            debug!(
+                "setting uid: req={} ino={} uid={:?}",
+                req.unique(),
+                inode,
+                uid
             );
-            if let Err(_) = result {
-                reply.error(libc::EINVAL);
+            attrs.uid = uid;
+            let result = with_retry(|| {
+                self.client.execute(
+                    "UPDATE inodes SET uid = $1, ctime = now() WHERE ino = $2",
+                    &[&(uid as i32), &(inode as i32)],
+                )
+            });
+            if let Err(err) = result {
+                reply.error(self.db_errno("setattr", inode, &err));
                 return;
             }
         }
 
-        // This is truncate()
+        // This is truncate(). Lines cannot be cut, so files can only
+        // be truncated to zero bytes, which is what opening a file with
+        // O_TRUNC does.
         if let Some(size) = size {
-            debug!("setting size: ino={} size={:?}", inode, size);
-            reply.error(libc::EPERM);
-            return;
+            debug!(
+                "setting size: req={} ino={} size={:?}",
+                req.unique(),
+                inode,
+                size
+            );
+            if size != 0 {
+                reply.error(libc::EPERM);
+                return;
+            }
+            let result = self
+                .check_writable()
+                .and_then(|()| self.truncate_inode("setattr", inode));
+            match self.check_write(result) {
+                Ok(truncated) => attrs = truncated,
+                Err(errno) => {
+                    reply.error(errno);
+                    return;
+                }
+            }
         }
 
         if let Some(atime) = atime {
-            debug!("setting atime: ino={} atime={:?}", inode, atime);
+            debug!(
+                "setting atime: req={} ino={} atime={:?}",
+                req.unique(),
+                inode,
+                atime
+            );
             // Does not do anything right now.
             attrs.atime = match atime {
                 TimeOrNow::SpecificTime(time) => time,
@@ -396,7 +4018,12 @@ impl Filesystem for DatabaseFS {
 
         if let Some(mtime) = mtime {
             // Does not do anything right now.
-            debug!("setting mtime: ino={} mtime={:?}", inode, mtime);
+            debug!(
+                "setting mtime: req={} ino={} mtime={:?}",
+                req.unique(),
+                inode,
+                mtime
+            );
             attrs.mtime = match mtime {
                 TimeOrNow::SpecificTime(time) => time,
                 TimeOrNow::Now => SystemTime::now(),
@@ -406,67 +4033,215 @@ impl Filesystem for DatabaseFS {
         reply.attr(&ZERO, &attrs);
     }
 
-    fn opendir(&mut self, _req: &Request, inode: u64, _flags: i32, reply: ReplyOpen) {
-        debug!("opendir() called with {:?}", inode);
+    fn open(&mut self, req: &Request, inode: u64, flags: i32, reply: ReplyOpen) {
+        self.status.touch();
+        debug!(
+            "open() called with req={} ino={} flags={:#x}",
+            req.unique(),
+            inode,
+            flags
+        );
+        // The contents of the virtual files changes all the time, so
+        // bypass the page cache to not serve truncated or stale data.
+        if DatabaseFS::is_virtual(inode) {
+            reply.opened(0, FOPEN_DIRECT_IO);
+        } else if let Err(errno) = self.check_open_files() {
+            reply.error(errno);
+        } else {
+            // The stream file grows with every write, so bypass the
+            // page cache to read up to the current end rather than the
+            // size the kernel has cached.
+            let open_flags = if self.is_stream(inode) {
+                FOPEN_DIRECT_IO
+            } else {
+                0
+            };
+            let fh = self.open_handle(inode, flags);
+            reply.opened(fh, open_flags);
+        }
+    }
 
-        // We only allow reading the top directory
-        if inode != FUSE_ROOT_ID {
-            reply.error(ENOENT);
-            return;
+    fn release(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.status.touch();
+        debug!(
+            "release() called with req={} ino={} fh={}",
+            req.unique(),
+            ino,
+            fh
+        );
+        // The writes are normally inserted when the file is flushed on
+        // close, so this only does anything if that was not done.
+        if let Err(errno) = self.flush_handle(fh) {
+            error!(
+                "writes to inode {} through fh {} lost: errno {}",
+                ino, fh, errno
+            );
+        }
+        self.handles.remove(&fh);
+        reply.ok();
+    }
+
+    /// Called on each close of a file descriptor, and the error is
+    /// returned from the close.
+    fn flush(&mut self, req: &Request<'_>, ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        self.status.touch();
+        debug!(
+            "flush() called with req={} ino={} fh={}",
+            req.unique(),
+            ino,
+            fh
+        );
+        match self.flush_handle(fh) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn read(
+        &mut self,
+        req: &Request,
+        inode: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        self.status.touch();
+        debug!(
+            "read() called with req={} ino={} offset={} size={}",
+            req.unique(),
+            inode,
+            offset,
+            size
+        );
+        if self.is_directory(inode) {
+            reply.error(libc::EISDIR);
+        } else if let Some(contents) = self.virtual_contents(inode) {
+            let start = (offset as usize).min(contents.len());
+            let end = (start + size as usize).min(contents.len());
+            reply.data(&contents.as_bytes()[start..end]);
+        } else if let Some(RecordNode::Record(seq)) = self.record_node(inode) {
+            match self.get_record(seq) {
+                Ok(record) => {
+                    let start = (offset as usize).min(record.len());
+                    let end = (start + size as usize).min(record.len());
+                    reply.data(&record.as_bytes()[start..end]);
+                }
+                Err(errno) => reply.error(errno),
+            }
+        } else if let Some(file) = stats_file(inode) {
+            match self.stats_contents(file) {
+                Ok(contents) => {
+                    let start = (offset as usize).min(contents.len());
+                    let end = (start + size as usize).min(contents.len());
+                    reply.data(&contents.as_bytes()[start..end]);
+                }
+                Err(errno) => reply.error(errno),
+            }
+        } else if self.is_stream(inode) {
+            match self.read_stream(inode as i32, offset, size) {
+                Ok(data) => reply.data(&data),
+                Err(errno) => reply.error(errno),
+            }
+        } else {
+            reply.error(libc::ENOSYS);
         }
+    }
+
+    fn opendir(&mut self, req: &Request, inode: u64, _flags: i32, reply: ReplyOpen) {
+        self.status.touch();
+        debug!("opendir() called with req={} {:?}", req.unique(), inode);
 
-        let result = self.client.query(&self.directory_scan, &[]);
+        let result = if let Some(node) = self.record_node(inode) {
+            self.record_entries(node)
+        } else if inode == FUSE_ROOT_ID || inode == STATS_INODE {
+            // The files in the `.stats` directory have the same names
+            // as the files in the root directory.
+            let flag = if inode == STATS_INODE { STATS_FLAG } else { 0 };
+            let show_stats = inode == FUSE_ROOT_ID && self.show_stats;
+            self.scan_directory().map(|rows| {
+                let mut entries: Vec<_> = rows
+                    .iter()
+                    .map(|row| {
+                        let name: &str = row.get("name");
+                        let ino: i32 = row.get("ino");
+                        (name.to_string(), ino as u64 | flag, FileType::RegularFile)
+                    })
+                    .collect();
+                if show_stats {
+                    entries.push((STATS_NAME.to_string(), STATS_INODE, FileType::Directory));
+                }
+                entries
+            })
+        } else {
+            reply.error(ENOENT);
+            return;
+        };
 
         match result {
             Ok(files) => {
-                self.entries = Some(files);
-                reply.opened(42, 0);
+                self.last_fh += 1;
+                self.dir_handles.insert(self.last_fh, files);
+                reply.opened(self.last_fh, 0);
             }
-            Err(_) => reply.error(libc::EBADF),
+            Err(err) => reply.error(self.db_errno("opendir", inode, &err)),
         }
     }
 
-    fn releasedir(
-        &mut self,
-        _req: &Request<'_>,
-        ino: u64,
-        fh: u64,
-        _flags: i32,
-        reply: ReplyEmpty,
-    ) {
-        debug!("releasedir() called with ino={} fh={}", ino, fh);
-        self.entries = None;
+    fn releasedir(&mut self, req: &Request<'_>, ino: u64, fh: u64, _flags: i32, reply: ReplyEmpty) {
+        self.status.touch();
+        debug!(
+            "releasedir() called with req={} ino={} fh={}",
+            req.unique(),
+            ino,
+            fh
+        );
+        self.dir_handles.remove(&fh);
         reply.ok();
     }
 
     fn readdir(
         &mut self,
-        _req: &Request,
+        req: &Request,
         inode: u64,
         fh: u64,
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
-        debug!("readdir() called with fh={} ino={}", fh, inode);
+        self.status.touch();
+        debug!(
+            "readdir() called with req={} fh={} ino={}",
+            req.unique(),
+            fh,
+            inode
+        );
 
-        // We only allow reading the top directory
-        if fh != 42 {
-            reply.error(libc::EINVAL);
-            return;
-        }
+        let entries = match self.dir_handles.get(&fh) {
+            Some(entries) => entries,
+            None => {
+                reply.error(libc::EBADF);
+                return;
+            }
+        };
 
-        // Need to handle the case that the buffer can be full, but we
-        // ignore that now.
-        if let Some(entries) = self.entries.take() {
-            for (index, row) in entries.iter().enumerate() {
-                let name: &str = row.get("name");
-                let ino: i32 = row.get("ino");
-                let _ = reply.add(
-                    ino as u64,
-                    offset + index as i64,
-                    FileType::RegularFile,
-                    name,
-                );
+        // The offset of an entry is the index of the next entry, so
+        // when the buffer is full the kernel calls again with the
+        // offset of the first entry that did not fit.
+        for (index, (name, ino, kind)) in entries.iter().enumerate().skip(offset as usize) {
+            let buffer_full = reply.add(*ino, index as i64 + 1, *kind, name);
+            if buffer_full {
+                break;
             }
         }
         reply.ok();
@@ -479,44 +4254,224 @@ impl Filesystem for DatabaseFS {
         name: &OsStr,
         mode: u32,
         _umask: u32,
-        _flags: i32,
+        flags: i32,
         reply: ReplyCreate,
     ) {
+        self.status.touch();
+        debug!(
+            "create() called with req={} parent={} name={:?}",
+            req.unique(),
+            parent,
+            name
+        );
         if parent != FUSE_ROOT_ID {
-            reply.error(libc::EBADFD);
-        } else if let Ok(_) = self.lookup_name(name.to_str().unwrap()) {
-            reply.error(libc::EEXIST);
-        } else {
-            match self.allocate_inode(name.to_str().unwrap(), req.uid(), req.gid(), mode) {
-                Ok(attrs) => {
-                    reply.created(&ZERO, &attrs, 0, 0, 0);
-                }
-                Err(err) => {
-                    debug!("query error {}", err);
-                    reply.error(libc::EBADFD);
-                }
+            reply.error(self.parent_errno(parent));
+            return;
+        }
+        if self.stream.is_some() && name != STREAM_FILE {
+            reply.error(libc::EPERM);
+            return;
+        }
+
+        // Check the limit before creating anything, so that a failed
+        // create does not leave a new file behind.
+        if let Err(errno) = self.check_open_files() {
+            reply.error(errno);
+            return;
+        }
+        if let Err(errno) = self.check_writable() {
+            reply.error(errno);
+            return;
+        }
+
+        let result = self.create_file(name.to_str().unwrap(), mode, req.uid(), req.gid(), flags);
+        match self.check_write(result) {
+            Ok((attrs, generation)) => {
+                let fh = self.open_handle(attrs.ino, flags);
+                reply.created(&ZERO, &attrs, generation, fh, 0);
             }
+            Err(errno) => reply.error(errno),
         }
     }
 
     fn write(
         &mut self,
-        _req: &Request,
+        req: &Request,
         inode: u64,
-        _fh: u64,
-        _offset: i64,
+        fh: u64,
+        offset: i64,
         data: &[u8],
         _write_flags: u32,
         #[allow(unused_variables)] flags: i32,
         _lock_owner: Option<u64>,
         reply: ReplyWrite,
     ) {
-        match self.write_inode(inode as i32, data) {
+        self.status.touch();
+        debug!(
+            "write() called with req={} ino={} offset={} size={}",
+            req.unique(),
+            inode,
+            offset,
+            data.len()
+        );
+        if self.is_directory(inode) {
+            reply.error(libc::EISDIR);
+            return;
+        }
+        if DatabaseFS::is_virtual(inode) {
+            reply.error(libc::EACCES);
+            return;
+        }
+
+        // Each line is stored as a single value, so refuse to store
+        // lines that are unreasonably large.
+        if data
+            .split(|&b| b == self.format.delimiter)
+            .any(|line| line.len() > self.max_line_bytes)
+        {
+            reply.error(libc::EFBIG);
+            return;
+        }
+
+        // Data is only ever appended, so the offset is the size of the
+        // file before the write.
+        if matches!(self.max_file_size, Some(max) if offset as u64 + data.len() as u64 > max) {
+            reply.error(libc::EFBIG);
+            return;
+        }
+
+        let writer = Writer::from(req);
+
+        // Writes through a handle opened with O_DIRECT are written
+        // straight to the database, without keeping them for close or
+        // queueing them, so that they are stored once the write
+        // returns. Queued writes are written first to keep the lines
+        // in order.
+        let direct = self
+            .handles
+            .get(&fh)
+            .is_some_and(|handle| handle.flags & libc::O_DIRECT != 0);
+        if direct {
+            if let Some(Err(errno)) = self.writer.as_ref().map(AsyncWriter::flush) {
+                reply.error(errno);
+                return;
+            }
+        } else if self.write_policy == WritePolicy::OnClose {
+            if split_lines(offset, data, self.format).is_err() {
+                reply.error(libc::EINVAL);
+                return;
+            }
+            let writer = self.annotation(&writer);
+            match self.handles.get_mut(&fh) {
+                Some(handle) => {
+                    handle.pending.push((offset, data.to_vec(), writer));
+                    reply.written(data.len() as u32);
+                }
+                None => reply.error(libc::EBADF),
+            }
+            return;
+        } else if let Some(queue) = &self.writer {
+            match queue.send(inode as i32, offset, data, self.annotation(&writer)) {
+                Ok(()) => reply.written(data.len() as u32),
+                Err(errno) => reply.error(errno),
+            }
+            return;
+        }
+
+        if let Err(errno) = self.check_writable() {
+            reply.error(errno);
+            return;
+        }
+        let result = self.write_inode(inode as i32, offset, data, &writer);
+        match self.check_write(result) {
             Ok(_) => reply.written(data.len() as u32),
-            Err(err) => {
-                debug!("query error: {}", err);
-                reply.error(libc::EBADF);
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn ioctl(
+        &mut self,
+        req: &Request,
+        inode: u64,
+        _fh: u64,
+        _flags: u32,
+        cmd: u32,
+        _in_data: &[u8],
+        _out_size: u32,
+        reply: ReplyIoctl,
+    ) {
+        self.status.touch();
+        debug!(
+            "ioctl() called with req={} ino={} cmd={:#x}",
+            req.unique(),
+            inode,
+            cmd
+        );
+        if cmd != COMMIT_IOCTL {
+            reply.error(libc::ENOTTY);
+            return;
+        }
+
+        // Synchronous writes are committed before replying, so there
+        // is only something to wait for when writing asynchronously.
+        let result = match &self.writer {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        };
+        match result {
+            Ok(()) => reply.ioctl(0, &[]),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn copy_file_range(
+        &mut self,
+        req: &Request<'_>,
+        ino_in: u64,
+        _fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        _fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        _flags: u32,
+        reply: ReplyWrite,
+    ) {
+        self.status.touch();
+        debug!(
+            "copy_file_range() called with req={} ino_in={} offset_in={} ino_out={} offset_out={} len={}", req.unique(),
+            ino_in, offset_in, ino_out, offset_out, len
+        );
+        if self.is_directory(ino_in) || self.is_directory(ino_out) {
+            reply.error(libc::EISDIR);
+            return;
+        }
+        if DatabaseFS::is_virtual(ino_out) {
+            reply.error(libc::EACCES);
+            return;
+        }
+        if DatabaseFS::is_virtual(ino_in) {
+            reply.error(libc::EOPNOTSUPP);
+            return;
+        }
+
+        // Queued writes to the source have to be in the database
+        // before copying it.
+        if let Some(writer) = &self.writer {
+            if let Err(errno) = writer.flush() {
+                reply.error(errno);
+                return;
             }
         }
+
+        if let Err(errno) = self.check_writable() {
+            reply.error(errno);
+            return;
+        }
+        let result = self.copy_inode(ino_in as i32, offset_in, ino_out as i32, offset_out, len);
+        match self.check_write(result) {
+            Ok(copied) => reply.written(copied as u32),
+            Err(errno) => reply.error(errno),
+        }
     }
 }
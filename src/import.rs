@@ -0,0 +1,51 @@
+//! Finding the files to import into a file system.
+//!
+//! Both file systems are flat, so only the regular files directly in
+//! the imported directory are imported, in the order of their names.
+//! Subdirectories, symbolic links, and other entries are skipped with
+//! a warning, so that importing the same directory into either file
+//! system gives the same files.
+
+use log::warn;
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Regular files directly in `dir`, sorted by name, with their
+/// metadata. Symbolic links are not followed.
+pub fn files(dir: &Path) -> io::Result<Vec<(PathBuf, Metadata)>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_file() {
+            files.push((entry.path(), metadata));
+        } else {
+            warn!("skipping {}: not a regular file", entry.path().display());
+        }
+    }
+    files.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn regular_files_only() {
+        let dir = std::env::temp_dir().join(format!("import-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("subdir")).unwrap();
+        fs::write(dir.join("b.log"), b"second\n").unwrap();
+        fs::write(dir.join("a.log"), b"first\n").unwrap();
+        std::os::unix::fs::symlink(dir.join("a.log"), dir.join("link.log")).unwrap();
+
+        let names: Vec<_> = files(&dir)
+            .unwrap()
+            .into_iter()
+            .map(|(path, _)| path.file_name().unwrap().to_owned())
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(names, ["a.log", "b.log"]);
+    }
+}
@@ -0,0 +1,78 @@
+//! Splitting of the data written to a file into lines.
+//!
+//! Both file systems store the data written to a file as lines, and
+//! split it the same way so that a capture gives the same lines
+//! whichever file system it is written to.
+
+/// Split data into lines separated by `delimiter`, returning each line
+/// together with its byte position in the data.
+///
+/// A delimiter ends a line, so data ending with a delimiter does not
+/// have an empty line after it. Other empty lines are skipped unless
+/// `keep_empty` is set.
+///
+/// Data is never held back waiting for a delimiter, so the data after
+/// the last delimiter, or all of it if there is no delimiter, is a
+/// complete line. A line written in several writes is thus stored as
+/// several lines.
+pub fn split(data: &[u8], delimiter: u8, keep_empty: bool) -> Vec<(usize, &[u8])> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for line in data.split(|&b| b == delimiter) {
+        // The line after the last delimiter is only empty when the
+        // data ends with a delimiter.
+        let last = start + line.len() == data.len();
+        if !line.is_empty() || (keep_empty && !last) {
+            lines.push((start, line));
+        }
+        start += line.len() + 1;
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(data: &[u8], keep_empty: bool) -> Vec<&[u8]> {
+        split(data, b'\n', keep_empty)
+            .into_iter()
+            .map(|(_, line)| line)
+            .collect()
+    }
+
+    #[test]
+    fn trailing_delimiter() {
+        for keep_empty in [false, true] {
+            assert_eq!(lines(b"a\nb\n", keep_empty), [b"a", b"b"]);
+            assert_eq!(lines(b"a\nb", keep_empty), [b"a", b"b"]);
+            assert_eq!(lines(b"", keep_empty), [b""; 0]);
+        }
+        assert_eq!(lines(b"\n", false), [b""; 0]);
+        assert_eq!(lines(b"\n", true), [b""]);
+    }
+
+    #[test]
+    fn empty_lines() {
+        assert_eq!(lines(b"\na\n\nb\n", false), [&b"a"[..], b"b"]);
+        assert_eq!(lines(b"\na\n\nb\n", true), [&b""[..], b"a", b"", b"b"]);
+        assert_eq!(lines(b"\n\n", true), [b"", b""]);
+    }
+
+    #[test]
+    fn no_delimiter() {
+        for keep_empty in [false, true] {
+            assert_eq!(lines(b"partial", keep_empty), [b"partial"]);
+            assert_eq!(split(b"partial", b'\n', keep_empty), [(0, &b"partial"[..])]);
+        }
+    }
+
+    #[test]
+    fn positions() {
+        assert_eq!(
+            split(b"ab\n\ncd\n", b'\n', true),
+            [(0, &b"ab"[..]), (3, b""), (4, b"cd")]
+        );
+        assert_eq!(split(b"ab;;cd", b';', false), [(0, &b"ab"[..]), (4, b"cd")]);
+    }
+}
@@ -0,0 +1,82 @@
+//! Log file that is rotated when it grows too large.
+//!
+//! When the file reaches the maximum size, it is renamed with a `.1`
+//! suffix, any older files are shifted one step up (`.1` becomes `.2`
+//! and so on), and a new file is started. Only the given number of
+//! old files is kept.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+/**
+ * Writer appending to a log file and rotating it by size.
+ *
+ * Rotation is only done between writes, so a log line is never split
+ * over two files. A single write larger than the maximum size is
+ * written to a file of its own.
+ */
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    /// Number of rotated files to keep. If zero, the file is
+    /// truncated instead of rotated.
+    count: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    pub fn open(path: &str, max_size: u64, count: usize) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: PathBuf::from(path),
+            max_size,
+            count,
+            file,
+            size,
+        })
+    }
+
+    /// Path of the rotated file with the given index.
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.count > 0 {
+            for index in (1..self.count).rev() {
+                let from = self.rotated(index);
+                if from.exists() {
+                    fs::rename(&from, self.rotated(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
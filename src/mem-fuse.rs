@@ -1,27 +1,78 @@
 use clap::{crate_version, Arg, Command};
 //use daemonize::Daemonize;
+use fuser::consts::FUSE_BIG_WRITES;
 use fuser::{
     FileAttr, FileType, Filesystem, KernelConfig, MountOption, ReplyAttr, ReplyCreate,
-    ReplyDirectory, ReplyEntry, ReplyWrite, Request, FUSE_ROOT_ID,
+    ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyWrite, ReplyXattr, Request, Session,
+    TimeOrNow, FUSE_ROOT_ID,
 };
 use libc::{c_int, ENOENT};
-use log::{debug, error, LevelFilter};
+use log::{debug, error, info, warn, LevelFilter};
+use serde_json::{json, Value};
 use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::error::Error;
 use std::ffi::OsStr;
-use std::fs;
-use std::io::ErrorKind;
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::fs::MetadataExt;
 use std::os::unix::prelude::OsStrExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str::{from_utf8, Utf8Error};
+use std::thread;
 use std::time::SystemTime;
 use std::time::{Duration, UNIX_EPOCH};
 
+mod import;
+mod lines;
+
 const MAX_NAME_LENGTH: u32 = 255;
-const BLOCK_SIZE: u64 = 512;
 
+/// Default preferred I/O size reported in `st_blksize`.
+const DEFAULT_IO_BLOCK_SIZE: &str = "512";
+
+/// FUSE protocol version used by fuser given the `abi-7-*` features
+/// enabled. The kernel will use the lower of this and the version it
+/// supports itself.
+const FUSE_PROTOCOL_VERSION: &str = "7.28";
+
+/// Default maximum size of a write request. The kernel default is
+/// 128 KiB, but a larger size means that big writes arrive in fewer
+/// calls.
+const DEFAULT_MAX_WRITE: &str = "1048576";
+
+/// Default number of times to retry mounting when the mount point is
+/// busy.
+const DEFAULT_MOUNT_RETRIES: &str = "3";
+
+/// Default delay in milliseconds between attempts to mount.
+const DEFAULT_MOUNT_RETRY_DELAY: &str = "100";
+
+/// Name of the extended attribute with the number of lines in a file.
+const LINE_COUNT_XATTR: &str = "user.line_count";
+
+/// Reply with the value of an extended attribute. The kernel first
+/// asks with a zero size to find out how large the value is.
+fn reply_xattr(value: &[u8], size: u32, reply: ReplyXattr) {
+    if size == 0 {
+        reply.size(value.len() as u32);
+    } else if value.len() > size as usize {
+        reply.error(libc::ERANGE);
+    } else {
+        reply.data(value);
+    }
+}
+
+/// Version of the format of the file metadata saved in the data
+/// directory. Metadata with a newer version is refused, but fields
+/// that are not known are ignored.
+const METADATA_VERSION: u64 = 1;
+
+/// Attributes of the root directory of the mount. The kernel always
+/// refers to the root directory using `FUSE_ROOT_ID`, so that has to
+/// be the inode.
 const CAPTURE_DIR_ATTR: FileAttr = FileAttr {
-    ino: 1,
+    ino: FUSE_ROOT_ID,
     size: 0,
     blocks: 0,
     atime: UNIX_EPOCH, // 1970-01-01 00:00:00
@@ -41,6 +92,14 @@ const CAPTURE_DIR_ATTR: FileAttr = FileAttr {
 fn main() {
     let matches = Command::new("mem-fuse")
         .version(crate_version!())
+        .long_version(
+            format!(
+                "{} (FUSE protocol {})",
+                crate_version!(),
+                FUSE_PROTOCOL_VERSION
+            )
+            .as_str(),
+        )
         .author("Mats Kindahl")
         .arg(
             Arg::new("data-dir")
@@ -62,6 +121,90 @@ fn main() {
                 .help("Database connection parameters")
                 .takes_value(true),
         )
+        .arg(
+            Arg::new("max-write")
+                .long("max-write")
+                .value_name("BYTES")
+                .default_value(DEFAULT_MAX_WRITE)
+                .validator(|s| s.parse::<u32>())
+                .help("Maximum size of a single write request from the kernel")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("max-file-size")
+                .long("max-file-size")
+                .value_name("BYTES")
+                .validator(|s| s.parse::<u64>())
+                .help("Maximum size of a file, writes beyond it are rejected with EFBIG")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("delimiter")
+                .long("delimiter")
+                .value_name("BYTE")
+                .default_value("\\n")
+                .validator(parse_delimiter)
+                .help("Byte separating records, either a character or an escape like \\0 or \\x1e")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("keep-empty-lines")
+                .long("keep-empty-lines")
+                .help("Store empty lines instead of skipping them"),
+        )
+        .arg(
+            Arg::new("io-block-size")
+                .long("io-block-size")
+                .value_name("BYTES")
+                .default_value(DEFAULT_IO_BLOCK_SIZE)
+                .validator(|s| match s.parse::<u32>() {
+                    Ok(0) => Err("must be positive".to_string()),
+                    Ok(_) => Ok(()),
+                    Err(err) => Err(err.to_string()),
+                })
+                .help("Preferred I/O size reported to clients in st_blksize")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("subtype")
+                .long("subtype")
+                .value_name("NAME")
+                .default_value("memfuse")
+                .validator(|s| {
+                    if s.is_empty() || s.contains(|c: char| c == ',' || c.is_whitespace()) {
+                        Err("must be a non-empty name without commas or whitespace")
+                    } else {
+                        Ok(())
+                    }
+                })
+                .help("File system subtype, shown as fuse.NAME in /proc/mounts")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("import")
+                .long("import")
+                .value_name("DIR")
+                .help("Import the files in DIR before mounting")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("mount-retries")
+                .long("mount-retries")
+                .value_name("COUNT")
+                .default_value(DEFAULT_MOUNT_RETRIES)
+                .validator(|s| s.parse::<u32>())
+                .help("Number of times to retry mounting while the mount point is busy")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("mount-retry-delay")
+                .long("mount-retry-delay")
+                .value_name("MS")
+                .default_value(DEFAULT_MOUNT_RETRY_DELAY)
+                .validator(|s| s.parse::<u64>())
+                .help("Milliseconds to wait between attempts to mount")
+                .takes_value(true),
+        )
         .arg(
             Arg::new("v")
                 .short('v')
@@ -87,11 +230,50 @@ fn main() {
         MountOption::RW,
         MountOption::NoExec,
         MountOption::FSName("hello".to_string()),
+        MountOption::Subtype(matches.value_of("subtype").unwrap().to_string()),
     ];
 
     let params: String = matches.value_of("params").unwrap().to_string();
-    let filesystem = CaptureFS::new(params, data_dir).unwrap();
-    debug!("Filesystem created");
+    let max_write: u32 = matches.value_of_t("max-write").unwrap();
+    let io_block_size: u32 = matches.value_of_t("io-block-size").unwrap();
+    let delimiter = parse_delimiter(matches.value_of("delimiter").unwrap()).unwrap();
+    let keep_empty_lines = matches.is_present("keep-empty-lines");
+    let max_file_size = matches
+        .value_of("max-file-size")
+        .map(|bytes| bytes.parse().unwrap());
+    let import_dir = matches.value_of("import").map(PathBuf::from);
+    let retries: u32 = matches.value_of_t("mount-retries").unwrap();
+    let retry_delay = Duration::from_millis(matches.value_of_t("mount-retry-delay").unwrap());
+    let create_filesystem = || {
+        CaptureFS::new(
+            params.clone(),
+            data_dir.clone(),
+            max_write,
+            io_block_size,
+            delimiter,
+            keep_empty_lines,
+            max_file_size,
+        )
+    };
+
+    // The imported files are saved in the data directory, which the
+    // file system created for each attempt to mount loads them from.
+    if let Some(dir) = &import_dir {
+        if data_dir.is_empty() {
+            error!("import needs a data directory to save the files in");
+            std::process::exit(1);
+        }
+        if let Err(err) = create_filesystem().and_then(|mut filesystem| filesystem.import(dir)) {
+            error!("import failed: {}", err);
+            std::process::exit(1);
+        }
+    }
+
+    let new_filesystem = || {
+        let filesystem = create_filesystem().unwrap();
+        debug!("Filesystem created");
+        filesystem
+    };
 
     // let daemonize = Daemonize::new()
     //     .pid_file("/tmp/test.pid")
@@ -105,7 +287,8 @@ fn main() {
     // };
 
     debug!("Mounting filesystem");
-    let result = fuser::mount2(filesystem, mountpoint, &options);
+    let result = mount(new_filesystem, &mountpoint, &options, retries, retry_delay)
+        .and_then(|mut session| session.run());
     debug!("Exiting filesystem: {:?}", result);
     if let Err(e) = result {
         // Return a special error code for permission denied, which usually indicates that
@@ -117,21 +300,168 @@ fn main() {
     }
 }
 
+/// Mount a file system, retrying a limited number of times if the
+/// mount point is busy, which it can be for a short while after an
+/// unmount. Other errors fail right away.
+///
+/// A failed mount consumes the file system, so a new one is created
+/// for each attempt.
+fn mount<FS: Filesystem>(
+    mut new_filesystem: impl FnMut() -> FS,
+    mountpoint: &str,
+    options: &[MountOption],
+    retries: u32,
+    delay: Duration,
+) -> io::Result<Session<FS>> {
+    let mut attempt = 0;
+    loop {
+        match Session::new(new_filesystem(), Path::new(mountpoint), options) {
+            Err(err) if attempt < retries && err.raw_os_error() == Some(libc::EBUSY) => {
+                attempt += 1;
+                warn!(
+                    "mount point {} is busy, retry {} of {} in {:?}",
+                    mountpoint, attempt, retries, delay
+                );
+                thread::sleep(delay);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Number of 512-byte blocks needed for `size` bytes, as reported in
+/// `st_blocks`.
+fn blocks(size: u64) -> u64 {
+    size.div_ceil(512)
+}
+
+/// Time as seconds since the epoch, as saved in the file metadata.
+fn to_secs(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/// Metadata of a file as a JSON object. Names are saved as strings if
+/// they are valid UTF-8 and as arrays of bytes otherwise.
+fn metadata_json(name: &[u8], attr: &FileAttr) -> Value {
+    let name = match from_utf8(name) {
+        Ok(name) => json!(name),
+        Err(_) => json!(name),
+    };
+    json!({
+        "version": METADATA_VERSION,
+        "ino": attr.ino,
+        "name": name,
+        "mode": attr.perm,
+        "uid": attr.uid,
+        "gid": attr.gid,
+        "size": attr.size,
+        "atime": to_secs(attr.atime),
+        "mtime": to_secs(attr.mtime),
+        "ctime": to_secs(attr.ctime),
+        "crtime": to_secs(attr.crtime),
+    })
+}
+
+/// Parse the metadata of a file saved by `metadata_json` into the name
+/// and the attributes of the file.
+fn parse_metadata(value: &Value, blksize: u32) -> Result<(Vec<u8>, FileAttr), String> {
+    let int = |key: &str| {
+        value[key]
+            .as_u64()
+            .ok_or_else(|| format!("missing or invalid {:?}", key))
+    };
+    let time = |key: &str| {
+        value[key]
+            .as_f64()
+            .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+            .map(|since_epoch| UNIX_EPOCH + since_epoch)
+            .ok_or_else(|| format!("missing or invalid {:?}", key))
+    };
+
+    let version = int("version")?;
+    if version > METADATA_VERSION {
+        return Err(format!("unsupported metadata version {}", version));
+    }
+    let name = match &value["name"] {
+        Value::String(name) => name.as_bytes().to_vec(),
+        Value::Array(bytes) => bytes
+            .iter()
+            .map(|byte| byte.as_u64().and_then(|byte| u8::try_from(byte).ok()))
+            .collect::<Option<Vec<u8>>>()
+            .ok_or("invalid \"name\"")?,
+        _ => return Err("missing or invalid \"name\"".to_string()),
+    };
+    let ino = int("ino")?;
+    if ino <= FUSE_ROOT_ID {
+        return Err(format!("invalid inode {}", ino));
+    }
+    let size = int("size")?;
+    let attr = FileAttr {
+        ino,
+        size,
+        blocks: blocks(size),
+        atime: time("atime")?,
+        mtime: time("mtime")?,
+        ctime: time("ctime")?,
+        crtime: time("crtime")?,
+        kind: FileType::RegularFile,
+        perm: int("mode")? as u16,
+        nlink: 0,
+        uid: int("uid")? as u32,
+        gid: int("gid")? as u32,
+        rdev: 0,
+        flags: 0,
+        blksize,
+    };
+    Ok((name, attr))
+}
+
+/// Parse a delimiter, which is either a single ASCII character or one
+/// of the escapes `\0`, `\n`, `\r`, `\t`, and `\xHH`.
+///
+/// Records are stored as text, so the delimiter has to be ASCII.
+fn parse_delimiter(spec: &str) -> Result<u8, String> {
+    let byte = match spec {
+        "\\0" => 0,
+        "\\n" => b'\n',
+        "\\r" => b'\r',
+        "\\t" => b'\t',
+        _ => match spec.strip_prefix("\\x") {
+            Some(hex) => u8::from_str_radix(hex, 16)
+                .map_err(|err| format!("bad delimiter {:?}: {}", spec, err))?,
+            None if spec.len() == 1 => spec.as_bytes()[0],
+            None => return Err(format!("expected a single character, got {:?}", spec)),
+        },
+    };
+    if !byte.is_ascii() {
+        return Err(format!("delimiter {:?} is not ASCII", spec));
+    }
+    Ok(byte)
+}
+
 /// This just contain file attributes and data directly.
 struct FileData {
+    name: Vec<u8>,
     lines: Vec<String>,
     attr: FileAttr,
 }
 
 impl FileData {
-    fn new(attr: FileAttr) -> FileData {
+    fn new(name: Vec<u8>, attr: FileAttr) -> FileData {
         let lines = Vec::new();
-        FileData { lines, attr }
+        FileData { name, lines, attr }
     }
 
     fn add_line(&mut self, string: String) {
         self.lines.push(string);
     }
+
+    fn set_size(&mut self, size: u64) {
+        self.attr.size = size;
+        self.attr.blocks = blocks(size);
+    }
 }
 
 /**
@@ -146,34 +476,450 @@ impl FileData {
  * (so this is hard-coded in the code below).
  */
 struct CaptureFS {
+    /// Directory to save the metadata and contents of the files in,
+    /// so that the files are still there after a restart. Nothing is
+    /// saved if this is empty.
     data_dir: String,
+    /// Attributes of the root directory. The modification time is
+    /// updated whenever an entry is added to the directory.
+    root_attr: FileAttr,
+    max_write: u32,
+    /// Preferred I/O size reported for files.
+    io_block_size: u32,
+    /// Byte separating the lines written to a file.
+    delimiter: u8,
+    /// Store empty lines instead of skipping them.
+    keep_empty_lines: bool,
+    /// Maximum number of bytes in a file, if limited.
+    max_file_size: Option<u64>,
     last_inode: u64,
     names: HashMap<Vec<u8>, u64>,
     files: BTreeMap<u64, FileData>,
+    last_fh: u64,
+    /// Directory entries for each open directory handle, captured
+    /// when the directory was opened.
+    dir_handles: HashMap<u64, Vec<(Vec<u8>, u64)>>,
 }
 
 impl CaptureFS {
-    fn new(_params: String, data_dir: String) -> Result<CaptureFS, postgres::Error> {
-        Ok(CaptureFS {
+    fn new(
+        _params: String,
+        data_dir: String,
+        max_write: u32,
+        io_block_size: u32,
+        delimiter: u8,
+        keep_empty_lines: bool,
+        max_file_size: Option<u64>,
+    ) -> Result<CaptureFS, Box<dyn Error>> {
+        let mut fs = CaptureFS {
             last_inode: FUSE_ROOT_ID,
             data_dir,
+            root_attr: CAPTURE_DIR_ATTR,
+            max_write,
+            io_block_size,
+            delimiter,
+            keep_empty_lines,
+            max_file_size,
             names: HashMap::new(),
             files: BTreeMap::new(),
-        })
+            last_fh: 0,
+            dir_handles: HashMap::new(),
+        };
+        if let (Some(inodes_dir), Some(contents_dir)) = (fs.inodes_dir(), fs.contents_dir()) {
+            for dir in [inodes_dir, contents_dir] {
+                fs::create_dir_all(&dir)
+                    .map_err(|err| format!("creating {} failed: {}", dir.display(), err))?;
+            }
+        }
+        fs.load_inodes()?;
+        Ok(fs)
+    }
+
+    /// Directory with the metadata of each file, if it is saved.
+    fn inodes_dir(&self) -> Option<PathBuf> {
+        if self.data_dir.is_empty() {
+            None
+        } else {
+            Some(Path::new(&self.data_dir).join("inodes"))
+        }
+    }
+
+    /// Directory with the contents of each file, if it is saved.
+    fn contents_dir(&self) -> Option<PathBuf> {
+        if self.data_dir.is_empty() {
+            None
+        } else {
+            Some(Path::new(&self.data_dir).join("contents"))
+        }
+    }
+
+    /// Save lines of a file in the data directory, each followed by the
+    /// delimiter. The lines are added to the saved lines unless
+    /// `truncate` is set, in which case they replace them.
+    ///
+    /// This is done before the lines are added in memory, so a failed
+    /// save leaves the file as it was.
+    fn save_lines(&self, ino: u64, lines: &[String], truncate: bool) -> Result<(), c_int> {
+        let dir = match self.contents_dir() {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        let path = dir.join(ino.to_string());
+        let mut data = Vec::new();
+        for line in lines {
+            data.extend_from_slice(line.as_bytes());
+            data.push(self.delimiter);
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(!truncate)
+            .write(true)
+            .truncate(truncate)
+            .open(&path)
+            .and_then(|mut file| file.write_all(&data))
+            .map_err(|err| {
+                error!("saving contents to {} failed: {}", path.display(), err);
+                libc::EIO
+            })
+    }
+
+    /// Load the lines of a file saved by `save_lines`, if any.
+    fn load_lines(&self, ino: u64) -> Result<Vec<String>, Box<dyn Error>> {
+        let path = match self.contents_dir() {
+            Some(dir) => dir.join(ino.to_string()),
+            None => return Ok(Vec::new()),
+        };
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let data = data.strip_suffix(&[self.delimiter]).unwrap_or(&data);
+        if data.is_empty() {
+            return Ok(Vec::new());
+        }
+        data.split(|&byte| byte == self.delimiter)
+            .map(|line| Ok(from_utf8(line)?.to_string()))
+            .collect::<Result<_, Utf8Error>>()
+            .map_err(|err| format!("{}: {}", path.display(), err).into())
+    }
+
+    /// Save the metadata of a file in the data directory.
+    ///
+    /// The metadata is written to a temporary file that then replaces
+    /// the old one, so a crash never leaves a partially written file.
+    fn save_inode(&self, ino: u64) -> Result<(), c_int> {
+        let dir = match self.inodes_dir() {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        let data = self.files.get(&ino).ok_or(libc::EBADF)?;
+        let path = dir.join(format!("{}.json", ino));
+        let temp = dir.join(format!("{}.json.tmp", ino));
+        fs::write(&temp, metadata_json(&data.name, &data.attr).to_string())
+            .and_then(|()| fs::rename(&temp, &path))
+            .map_err(|err| {
+                error!("saving metadata to {} failed: {}", path.display(), err);
+                libc::EIO
+            })
+    }
+
+    /// Load the metadata of the files saved in the data directory.
+    fn load_inodes(&mut self) -> Result<(), Box<dyn Error>> {
+        let dir = match self.inodes_dir() {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension() != Some(OsStr::new("json")) {
+                continue;
+            }
+            let value: Value = serde_json::from_str(&fs::read_to_string(&path)?)?;
+            let (name, attr) = parse_metadata(&value, self.io_block_size)
+                .map_err(|err| format!("{}: {}", path.display(), err))?;
+            self.last_inode = self.last_inode.max(attr.ino);
+            let mut data = FileData::new(name.clone(), attr);
+            data.lines = self.load_lines(attr.ino)?;
+            self.names.insert(name, attr.ino);
+            self.files.insert(attr.ino, data);
+        }
+        info!("loaded {} files from {}", self.files.len(), dir.display());
+        Ok(())
+    }
+
+    /// Error for a parent directory other than the root directory.
+    ///
+    /// The file system is flat, so any other inode is either a regular
+    /// file or does not exist.
+    fn parent_errno(&self, parent: u64) -> c_int {
+        if self.files.contains_key(&parent) {
+            libc::ENOTDIR
+        } else {
+            ENOENT
+        }
+    }
+
+    /// Look up a name in the root directory and return the attributes.
+    fn lookup_name(&self, name: &[u8]) -> Result<FileAttr, c_int> {
+        let inode = self.names.get(name).ok_or(ENOENT)?;
+        match self.files.get(inode) {
+            Some(data) => Ok(data.attr),
+            None => {
+                // The name map and the file map should always be in
+                // sync, so this is a bug. There is no file to return,
+                // so the name is reported as missing.
+                error!(
+                    "inconsistent file map: name {:?} maps to inode {} which does not exist",
+                    OsStr::from_bytes(name),
+                    inode
+                );
+                Err(ENOENT)
+            }
+        }
+    }
+
+    /// Allocate a new inode for a regular file in the root directory.
+    fn allocate_inode(
+        &mut self,
+        name: &[u8],
+        mode: u32,
+        uid: u32,
+        gid: u32,
+    ) -> Result<FileAttr, c_int> {
+        if self.names.contains_key(name) {
+            return Err(libc::EEXIST);
+        }
+
+        self.last_inode += 1;
+        let now = SystemTime::now();
+        let data = FileData::new(
+            name.to_vec(),
+            FileAttr {
+                ino: self.last_inode,
+                size: 0,
+                atime: now,
+                mtime: now,
+                ctime: now,
+                crtime: now,
+                kind: FileType::RegularFile,
+                perm: mode as u16,
+                nlink: 0,
+                uid,
+                gid,
+                rdev: 0,
+                blocks: 0,
+                flags: 0,
+                blksize: self.io_block_size,
+            },
+        );
+        let attr = data.attr;
+        // A file that cannot be saved is not created at all, so the
+        // file system never has files that are lost on a restart.
+        self.files.insert(attr.ino, data);
+        if let Err(errno) = self.save_inode(attr.ino) {
+            self.files.remove(&attr.ino);
+            return Err(errno);
+        }
+        self.names.insert(name.to_vec(), attr.ino);
+        self.root_attr.mtime = attr.ctime;
+        self.root_attr.ctime = attr.ctime;
+        Ok(attr)
+    }
+
+    /// Create a file in the root directory, or open the existing one
+    /// unless `O_EXCL` is given in `flags`. The contents of an existing
+    /// file is discarded if `O_TRUNC` is given.
+    fn create_inode(
+        &mut self,
+        name: &[u8],
+        mode: u32,
+        uid: u32,
+        gid: u32,
+        flags: i32,
+    ) -> Result<FileAttr, c_int> {
+        match self.lookup_name(name) {
+            Ok(_) if flags & libc::O_EXCL != 0 => Err(libc::EEXIST),
+            Ok(attr) if flags & libc::O_TRUNC != 0 => self.truncate_inode(attr.ino),
+            Ok(attr) => Ok(attr),
+            Err(ENOENT) => self.allocate_inode(name, mode, uid, gid),
+            Err(errno) => Err(errno),
+        }
+    }
+
+    /// Discard the contents of a file, which changes its modification
+    /// and change times like any other write.
+    fn truncate_inode(&mut self, ino: u64) -> Result<FileAttr, c_int> {
+        if ino == FUSE_ROOT_ID {
+            return Err(libc::EISDIR);
+        }
+        if !self.files.contains_key(&ino) {
+            return Err(ENOENT);
+        }
+        self.save_lines(ino, &[], true)?;
+        let data = self.files.get_mut(&ino).unwrap();
+        data.lines.clear();
+        data.set_size(0);
+        let now = SystemTime::now();
+        data.attr.mtime = now;
+        data.attr.ctime = now;
+        let attr = data.attr;
+        self.save_inode(ino)?;
+        Ok(attr)
+    }
+
+    /// Set the access and modification times of a file, where given.
+    fn set_times(
+        &mut self,
+        ino: u64,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+    ) -> Result<FileAttr, c_int> {
+        let data = self.files.get_mut(&ino).ok_or(ENOENT)?;
+        if atime.is_none() && mtime.is_none() {
+            return Ok(data.attr);
+        }
+        let now = SystemTime::now();
+        let time = |time| match time {
+            TimeOrNow::SpecificTime(time) => time,
+            TimeOrNow::Now => now,
+        };
+        if let Some(atime) = atime {
+            data.attr.atime = time(atime);
+        }
+        if let Some(mtime) = mtime {
+            data.attr.mtime = time(mtime);
+        }
+        data.attr.ctime = now;
+        let attr = data.attr;
+        self.save_inode(ino)?;
+        Ok(attr)
+    }
+
+    /// Split the data into lines and add them to the file.
+    fn write_inode(&mut self, ino: u64, data: &[u8]) -> Result<(), c_int> {
+        if ino == FUSE_ROOT_ID {
+            return Err(libc::EISDIR);
+        }
+        let size = self.files.get(&ino).ok_or(libc::EBADF)?.attr.size;
+        if matches!(self.max_file_size, Some(max) if size + data.len() as u64 > max) {
+            return Err(libc::EFBIG);
+        }
+        let lines: Result<Vec<_>, Utf8Error> =
+            lines::split(data, self.delimiter, self.keep_empty_lines)
+                .into_iter()
+                .map(|(_, line)| from_utf8(line).map(str::to_string))
+                .collect();
+        let lines = lines.map_err(|_| libc::EINVAL)?;
+        self.save_lines(ino, &lines, false)?;
+        let file_data = self.files.get_mut(&ino).unwrap();
+        for line in lines {
+            file_data.add_line(line)
+        }
+        file_data.set_size(size + data.len() as u64);
+        self.save_inode(ino)
+    }
+
+    /// Import the regular files in a directory, each as a file with the
+    /// same name, permissions, and owner, with its contents written as
+    /// a single write.
+    ///
+    /// The files are saved in the data directory like files written
+    /// through the mount. Files that already exist are skipped, as are
+    /// files that are not UTF-8.
+    fn import(&mut self, dir: &Path) -> Result<(), Box<dyn Error>> {
+        let mut count = 0;
+        for (path, metadata) in import::files(dir)? {
+            let data = fs::read(&path)?;
+            if let Err(err) = from_utf8(&data) {
+                warn!("skipping {}: {}", path.display(), err);
+                continue;
+            }
+            let name = path.file_name().unwrap().as_bytes();
+            let mode = metadata.mode() & 0o777;
+            let attr = match self.allocate_inode(name, mode, metadata.uid(), metadata.gid()) {
+                Ok(attr) => attr,
+                Err(libc::EEXIST) => {
+                    warn!("skipping {}: file exists", path.display());
+                    continue;
+                }
+                Err(errno) => return Err(io::Error::from_raw_os_error(errno).into()),
+            };
+            self.write_inode(attr.ino, &data)
+                .map_err(io::Error::from_raw_os_error)?;
+            debug!("imported {} into inode {}", path.display(), attr.ino);
+            count += 1;
+        }
+        info!("imported {} files from {}", count, dir.display());
+        Ok(())
+    }
+
+    /// Copy a range of one file to another file, returning the number
+    /// of bytes copied.
+    ///
+    /// Files are stored as lines rather than bytes, so only copying
+    /// a whole file to the end of a file is supported. Other ranges
+    /// fail with `EOPNOTSUPP`, which makes the kernel fall back to
+    /// reading and writing the data.
+    fn copy_inode(
+        &mut self,
+        ino_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        offset_out: i64,
+        len: u64,
+    ) -> Result<u64, c_int> {
+        if ino_in == FUSE_ROOT_ID || ino_out == FUSE_ROOT_ID {
+            return Err(libc::EISDIR);
+        }
+        let source = self.files.get(&ino_in).ok_or(libc::EBADF)?;
+        let size = source.attr.size;
+        let dest_size = self.files.get(&ino_out).ok_or(libc::EBADF)?.attr.size;
+        if offset_in != 0 || len < size || offset_out as u64 != dest_size || size > u32::MAX as u64
+        {
+            return Err(libc::EOPNOTSUPP);
+        }
+        if matches!(self.max_file_size, Some(max) if dest_size + size > max) {
+            return Err(libc::EFBIG);
+        }
+        let lines = source.lines.clone();
+        self.save_lines(ino_out, &lines, false)?;
+        let dest = self.files.get_mut(&ino_out).unwrap();
+        for line in lines {
+            dest.add_line(line);
+        }
+        dest.set_size(dest_size + size);
+        self.save_inode(ino_out)?;
+        Ok(size)
     }
 }
 
 impl Filesystem for CaptureFS {
-    fn init(&mut self, _req: &Request, _config: &mut KernelConfig) -> Result<(), c_int> {
-        fs::create_dir_all(Path::new(&self.data_dir).join("inodes")).unwrap();
-        fs::create_dir_all(Path::new(&self.data_dir).join("contents")).unwrap();
+    fn init(&mut self, _req: &Request, config: &mut KernelConfig) -> Result<(), c_int> {
+        info!("Using FUSE protocol version {}", FUSE_PROTOCOL_VERSION);
+        if let Err(nearest) = config.set_max_write(self.max_write) {
+            warn!(
+                "max write size {} not supported, using {}",
+                self.max_write, nearest
+            );
+            config.set_max_write(nearest).unwrap();
+        }
+        if let Err(unsupported) = config.add_capabilities(FUSE_BIG_WRITES) {
+            warn!("kernel does not support capabilities {:#x}", unsupported);
+        }
         Ok(())
     }
 
     /// Look up the name and return the attributes.
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
         debug!(
-            "lookup() called with parent={:?} name={:?}",
+            "lookup() called with req={} parent={:?} name={:?}",
+            req.unique(),
             parent,
             name.to_os_string().into_string()
         );
@@ -182,45 +928,123 @@ impl Filesystem for CaptureFS {
             return;
         }
 
-        if parent == FUSE_ROOT_ID {
-            if let Some(inode) = self.names.get(name.as_bytes()) {
-                if let Some(data) = self.files.get(inode) {
-                    reply.entry(&Duration::new(0, 0), &data.attr, 0);
-                    return;
-                } else {
-                    reply.error(libc::EBADFD);
-                    return;
-                }
-            }
+        if parent != FUSE_ROOT_ID {
+            reply.error(self.parent_errno(parent));
+            return;
+        }
+
+        match self.lookup_name(name.as_bytes()) {
+            Ok(attr) => reply.entry(&Duration::new(0, 0), &attr, 0),
+            Err(errno) => reply.error(errno),
         }
-        reply.error(ENOENT);
     }
 
-    fn forget(&mut self, _req: &Request, inode: u64, nlookup: u64) {
+    fn forget(&mut self, req: &Request, inode: u64, nlookup: u64) {
         debug!(
-            "forget() called with inode={:?} nlookup={:?}",
-            inode, nlookup
+            "forget() called with req={} inode={:?} nlookup={:?}",
+            req.unique(),
+            inode,
+            nlookup
         );
     }
 
-    fn getattr(&mut self, _req: &Request, inode: u64, reply: ReplyAttr) {
-        debug!("getattr() called with inode={:?}", inode);
+    fn getattr(&mut self, req: &Request, inode: u64, reply: ReplyAttr) {
+        debug!(
+            "getattr() called with req={} inode={:?}",
+            req.unique(),
+            inode
+        );
         if inode == FUSE_ROOT_ID {
-            reply.attr(&Duration::new(0, 0), &CAPTURE_DIR_ATTR);
+            debug_assert_eq!(self.root_attr.ino, FUSE_ROOT_ID);
+            reply.attr(&Duration::new(0, 0), &self.root_attr);
         } else if let Some(data) = self.files.get(&inode) {
             reply.attr(&Duration::new(0, 0), &data.attr);
+        } else {
+            reply.error(ENOENT);
         }
     }
 
-    fn readdir(
+    /// Change the attributes of a file. Files can only be truncated to
+    /// zero bytes, which is what opening a file with `O_TRUNC` does,
+    /// and have their access and modification times set.
+    fn setattr(
         &mut self,
-        _req: &Request,
+        req: &Request,
         inode: u64,
-        _fh: u64,
-        offset: i64,
-        mut reply: ReplyDirectory,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
     ) {
-        debug!("readdir() called with {:?}", inode);
+        debug!(
+            "setattr() called with req={} inode={} size={:?} atime={:?} mtime={:?}",
+            req.unique(),
+            inode,
+            size,
+            atime,
+            mtime
+        );
+        if inode == FUSE_ROOT_ID || mode.is_some() || uid.is_some() || gid.is_some() {
+            reply.error(libc::ENOSYS);
+            return;
+        }
+        let result = match size {
+            Some(0) => self.truncate_inode(inode).map(|_| ()),
+            Some(_) => Err(libc::EPERM),
+            None => Ok(()),
+        }
+        .and_then(|()| self.set_times(inode, atime, mtime));
+        match result {
+            Ok(attr) => reply.attr(&Duration::new(0, 0), &attr),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Get an extended attribute. The only one is the number of lines
+    /// of a file.
+    fn getxattr(&mut self, req: &Request, inode: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        debug!(
+            "getxattr() called with req={} inode={} name={:?}",
+            req.unique(),
+            inode,
+            name
+        );
+        match self.files.get(&inode) {
+            Some(data) if name == LINE_COUNT_XATTR => {
+                reply_xattr(data.lines.len().to_string().as_bytes(), size, reply)
+            }
+            Some(_) => reply.error(libc::ENODATA),
+            None if inode == FUSE_ROOT_ID => reply.error(libc::ENODATA),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn listxattr(&mut self, req: &Request, inode: u64, size: u32, reply: ReplyXattr) {
+        debug!(
+            "listxattr() called with req={} inode={}",
+            req.unique(),
+            inode
+        );
+        if self.files.contains_key(&inode) {
+            reply_xattr(format!("{}\0", LINE_COUNT_XATTR).as_bytes(), size, reply);
+        } else if inode == FUSE_ROOT_ID {
+            reply_xattr(&[], size, reply);
+        } else {
+            reply.error(ENOENT);
+        }
+    }
+
+    fn opendir(&mut self, req: &Request, inode: u64, _flags: i32, reply: ReplyOpen) {
+        debug!("opendir() called with req={} ino={}", req.unique(), inode);
 
         // We only allow reading the top directory
         if inode != FUSE_ROOT_ID {
@@ -228,10 +1052,54 @@ impl Filesystem for CaptureFS {
             return;
         }
 
-        for (index, (name, inode)) in self.names.iter().skip(offset as usize).enumerate() {
+        let entries = self
+            .names
+            .iter()
+            .map(|(name, inode)| (name.clone(), *inode))
+            .collect();
+        self.last_fh += 1;
+        self.dir_handles.insert(self.last_fh, entries);
+        reply.opened(self.last_fh, 0);
+    }
+
+    fn releasedir(&mut self, req: &Request<'_>, ino: u64, fh: u64, _flags: i32, reply: ReplyEmpty) {
+        debug!(
+            "releasedir() called with req={} ino={} fh={}",
+            req.unique(),
+            ino,
+            fh
+        );
+        self.dir_handles.remove(&fh);
+        reply.ok();
+    }
+
+    fn readdir(
+        &mut self,
+        req: &Request,
+        inode: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        debug!(
+            "readdir() called with req={} fh={} ino={}",
+            req.unique(),
+            fh,
+            inode
+        );
+
+        let entries = match self.dir_handles.get(&fh) {
+            Some(entries) => entries,
+            None => {
+                reply.error(libc::EBADF);
+                return;
+            }
+        };
+
+        for (index, (name, inode)) in entries.iter().enumerate().skip(offset as usize) {
             let buffer_full: bool = reply.add(
                 *inode,
-                offset + index as i64 + 1,
+                index as i64 + 1,
                 FileType::RegularFile,
                 OsStr::from_bytes(name),
             );
@@ -253,14 +1121,14 @@ impl Filesystem for CaptureFS {
         flags: i32,
         reply: ReplyCreate,
     ) {
-        debug!("create() called with {:?} {:?}", parent, name);
+        debug!(
+            "create() called with req={} parent={} name={:?}",
+            req.unique(),
+            parent,
+            name
+        );
         if parent != FUSE_ROOT_ID {
-            reply.error(libc::EBADFD);
-            return;
-        }
-
-        if self.names.contains_key(name.as_bytes()) {
-            reply.error(libc::EEXIST);
+            reply.error(self.parent_errno(parent));
             return;
         }
 
@@ -274,32 +1142,15 @@ impl Filesystem for CaptureFS {
                 return;
             }
         };
-        self.last_inode += 1;
-        self.names.insert(name.as_bytes().to_vec(), self.last_inode);
-        let data = FileData::new(FileAttr {
-            ino: self.last_inode,
-            size: 0,
-            atime: SystemTime::now(),
-            mtime: SystemTime::now(),
-            ctime: SystemTime::now(),
-            crtime: SystemTime::UNIX_EPOCH,
-            kind: FileType::RegularFile,
-            perm: mode as u16,
-            nlink: 0,
-            uid: req.uid(),
-            gid: req.gid(),
-            rdev: 0,
-            blocks: 0,
-            flags: 0,
-            blksize: BLOCK_SIZE as u32,
-        });
-        reply.created(&Duration::new(0, 0), &data.attr, 0, 0, 0);
-        self.files.insert(self.last_inode, data);
+        match self.create_inode(name.as_bytes(), mode, req.uid(), req.gid(), flags) {
+            Ok(attr) => reply.created(&Duration::new(0, 0), &attr, 0, 0, 0),
+            Err(errno) => reply.error(errno),
+        }
     }
 
     fn write(
         &mut self,
-        _req: &Request,
+        req: &Request,
         inode: u64,
         _fh: u64,
         _offset: i64,
@@ -310,19 +1161,353 @@ impl Filesystem for CaptureFS {
         reply: ReplyWrite,
     ) {
         debug!(
-            "write() called with inode={:?} size={:?}",
+            "write() called with req={} inode={:?} size={:?}",
+            req.unique(),
             inode,
             data.len()
         );
-        if let Some(file_data) = self.files.get_mut(&inode) {
-            let lines: Result<Vec<_>, Utf8Error> =
-                data.split(|&b| b == b'\n').map(|c| from_utf8(c)).collect();
-            for line in lines.unwrap() {
-                file_data.add_line(line.to_string())
-            }
-            reply.written(data.len() as u32);
-        } else {
-            reply.error(libc::EBADF);
+        match self.write_inode(inode, data) {
+            Ok(()) => reply.written(data.len() as u32),
+            Err(errno) => reply.error(errno),
         }
     }
+
+    fn copy_file_range(
+        &mut self,
+        req: &Request<'_>,
+        ino_in: u64,
+        _fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        _fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        _flags: u32,
+        reply: ReplyWrite,
+    ) {
+        debug!(
+            "copy_file_range() called with req={} ino_in={} offset_in={} ino_out={} offset_out={} len={}", req.unique(),
+            ino_in, offset_in, ino_out, offset_out, len
+        );
+        match self.copy_inode(ino_in, offset_in, ino_out, offset_out, len) {
+            Ok(copied) => reply.written(copied as u32),
+            Err(errno) => reply.error(errno),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_fs() -> CaptureFS {
+        new_fs_with("", None)
+    }
+
+    /// File system saving files in `data_dir`, unless it is empty, and
+    /// limiting files to `max_file_size` bytes.
+    fn new_fs_with(data_dir: &str, max_file_size: Option<u64>) -> CaptureFS {
+        CaptureFS::new(
+            String::new(),
+            data_dir.to_string(),
+            4096,
+            512,
+            b'\n',
+            false,
+            max_file_size,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn delimiters() {
+        assert_eq!(parse_delimiter("\\n"), Ok(b'\n'));
+        assert_eq!(parse_delimiter("\\0"), Ok(0));
+        assert_eq!(parse_delimiter("\\x1e"), Ok(0x1e));
+        assert_eq!(parse_delimiter(";"), Ok(b';'));
+        assert!(parse_delimiter("\\xff").is_err());
+        assert!(parse_delimiter("ab").is_err());
+    }
+
+    #[test]
+    fn write_with_delimiter() {
+        let mut fs = new_fs();
+        fs.delimiter = 0;
+        let attr = fs.allocate_inode(b"file", 0o644, 1000, 100).unwrap();
+        fs.write_inode(attr.ino, b"first line\n\0second").unwrap();
+        assert_eq!(fs.files[&attr.ino].lines, ["first line\n", "second"]);
+    }
+
+    #[test]
+    fn allocate_inode() {
+        let mut fs = new_fs();
+        let attr = fs.allocate_inode(b"first", 0o644, 1000, 100).unwrap();
+        assert_eq!(attr.kind, FileType::RegularFile);
+        assert_eq!(attr.perm, 0o644);
+        assert_eq!(attr.uid, 1000);
+        assert_eq!(attr.gid, 100);
+
+        assert_ne!(attr.crtime, UNIX_EPOCH);
+        assert_eq!(attr.crtime, attr.mtime);
+
+        let other = fs.allocate_inode(b"second", 0o600, 1000, 100).unwrap();
+        assert_ne!(attr.ino, other.ino);
+        assert_eq!(fs.lookup_name(b"first").unwrap().ino, attr.ino);
+        assert_eq!(fs.lookup_name(b"second").unwrap().ino, other.ino);
+    }
+
+    #[test]
+    fn allocate_existing_name() {
+        let mut fs = new_fs();
+        fs.allocate_inode(b"file", 0o644, 1000, 100).unwrap();
+        assert_eq!(
+            fs.allocate_inode(b"file", 0o644, 1000, 100),
+            Err(libc::EEXIST)
+        );
+    }
+
+    #[test]
+    fn parent_errors() {
+        let mut fs = new_fs();
+        let attr = fs.allocate_inode(b"file", 0o644, 1000, 100).unwrap();
+        assert_eq!(fs.parent_errno(attr.ino), libc::ENOTDIR);
+        assert_eq!(fs.parent_errno(4711), ENOENT);
+    }
+
+    #[test]
+    fn create_existing_name() {
+        let mut fs = new_fs();
+        let attr = fs.allocate_inode(b"file", 0o644, 1000, 100).unwrap();
+        fs.write_inode(attr.ino, b"line").unwrap();
+
+        let flags = libc::O_CREAT | libc::O_WRONLY;
+        assert_eq!(
+            fs.create_inode(b"file", 0o600, 1000, 100, flags | libc::O_EXCL),
+            Err(libc::EEXIST)
+        );
+        let opened = fs.create_inode(b"file", 0o600, 1000, 100, flags).unwrap();
+        assert_eq!(opened.ino, attr.ino);
+        assert_eq!(opened.size, 4);
+        assert_eq!(fs.files[&attr.ino].lines, vec!["line"]);
+        thread::sleep(Duration::from_millis(1));
+        let truncated = fs
+            .create_inode(b"file", 0o600, 1000, 100, flags | libc::O_TRUNC)
+            .unwrap();
+        assert_eq!(truncated.ino, attr.ino);
+        assert_eq!(truncated.size, 0);
+        assert_eq!(truncated.blocks, 0);
+        assert!(truncated.mtime > opened.mtime);
+        assert_eq!(truncated.ctime, truncated.mtime);
+        assert!(fs.files[&attr.ino].lines.is_empty());
+    }
+
+    #[test]
+    fn truncate_directory() {
+        let mut fs = new_fs();
+        assert_eq!(fs.truncate_inode(FUSE_ROOT_ID), Err(libc::EISDIR));
+        assert_eq!(fs.truncate_inode(4711), Err(ENOENT));
+    }
+
+    #[test]
+    fn copy_whole_file() {
+        let mut fs = new_fs();
+        let source = fs.allocate_inode(b"source", 0o644, 1000, 100).unwrap();
+        let dest = fs.allocate_inode(b"dest", 0o644, 1000, 100).unwrap();
+        fs.write_inode(source.ino, b"first\nsecond").unwrap();
+        fs.write_inode(dest.ino, b"zeroth").unwrap();
+
+        assert_eq!(fs.copy_inode(source.ino, 0, dest.ino, 6, 4096), Ok(12));
+        assert_eq!(fs.files[&dest.ino].lines, vec!["zeroth", "first", "second"]);
+        assert_eq!(fs.files[&dest.ino].attr.size, 18);
+    }
+
+    #[test]
+    fn copy_partial_range() {
+        let mut fs = new_fs();
+        let source = fs.allocate_inode(b"source", 0o644, 1000, 100).unwrap();
+        let dest = fs.allocate_inode(b"dest", 0o644, 1000, 100).unwrap();
+        fs.write_inode(source.ino, b"first\nsecond").unwrap();
+
+        assert_eq!(
+            fs.copy_inode(source.ino, 6, dest.ino, 0, 4096),
+            Err(libc::EOPNOTSUPP)
+        );
+        assert_eq!(
+            fs.copy_inode(source.ino, 0, dest.ino, 0, 5),
+            Err(libc::EOPNOTSUPP)
+        );
+        assert_eq!(
+            fs.copy_inode(source.ino, 0, dest.ino, 1, 4096),
+            Err(libc::EOPNOTSUPP)
+        );
+        assert!(fs.files[&dest.ino].lines.is_empty());
+    }
+
+    #[test]
+    fn lookup_missing_name() {
+        let fs = new_fs();
+        assert_eq!(fs.lookup_name(b"missing"), Err(ENOENT));
+    }
+
+    #[test]
+    fn lookup_inconsistent_name() {
+        let mut fs = new_fs();
+        fs.names.insert(b"ghost".to_vec(), 42);
+        assert_eq!(fs.lookup_name(b"ghost"), Err(ENOENT));
+    }
+
+    #[test]
+    fn write_lines() {
+        let mut fs = new_fs();
+        let attr = fs.allocate_inode(b"file", 0o644, 1000, 100).unwrap();
+        fs.write_inode(attr.ino, b"first\nsecond").unwrap();
+        fs.write_inode(attr.ino, b"third").unwrap();
+        assert_eq!(fs.files[&attr.ino].lines, vec!["first", "second", "third"]);
+        assert_eq!(fs.files[&attr.ino].attr.size, 17);
+        assert_eq!(fs.files[&attr.ino].attr.blocks, 1);
+
+        fs.write_inode(attr.ino, &[b'x'; 512]).unwrap();
+        assert_eq!(fs.files[&attr.ino].attr.size, 529);
+        assert_eq!(fs.files[&attr.ino].attr.blocks, 2);
+    }
+
+    #[test]
+    fn write_without_delimiter() {
+        let mut fs = new_fs();
+        let attr = fs.allocate_inode(b"file", 0o644, 1000, 100).unwrap();
+        fs.write_inode(attr.ino, b"first ").unwrap();
+        fs.write_inode(attr.ino, b"line\n").unwrap();
+        assert_eq!(fs.files[&attr.ino].lines, vec!["first ", "line"]);
+        assert_eq!(fs.files[&attr.ino].attr.size, 11);
+    }
+
+    #[test]
+    fn write_empty_lines() {
+        let mut fs = new_fs();
+        let attr = fs.allocate_inode(b"file", 0o644, 1000, 100).unwrap();
+        fs.write_inode(attr.ino, b"first\n\nsecond\n").unwrap();
+        assert_eq!(fs.files[&attr.ino].lines, vec!["first", "second"]);
+        assert_eq!(fs.files[&attr.ino].attr.size, 14);
+
+        fs.keep_empty_lines = true;
+        fs.write_inode(attr.ino, b"third\n\n").unwrap();
+        assert_eq!(
+            fs.files[&attr.ino].lines,
+            vec!["first", "second", "third", ""]
+        );
+    }
+
+    #[test]
+    fn import_files() {
+        let dir = std::env::temp_dir().join(format!("mem-fuse-import-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("capture.log"), b"first\nsecond\n").unwrap();
+        fs::write(dir.join("binary"), b"\xff\n").unwrap();
+
+        let mut fs = new_fs();
+        fs.allocate_inode(b"existing", 0o644, 1000, 100).unwrap();
+        fs::write(dir.join("existing"), b"ignored\n").unwrap();
+        fs.import(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let attr = fs.lookup_name(b"capture.log").unwrap();
+        assert_eq!(fs.files[&attr.ino].lines, vec!["first", "second"]);
+        assert_eq!(attr.size, 13);
+        assert_eq!(fs.lookup_name(b"binary"), Err(ENOENT));
+        let attr = fs.lookup_name(b"existing").unwrap();
+        assert!(fs.files[&attr.ino].lines.is_empty());
+    }
+
+    #[test]
+    fn import_into_data_dir() {
+        let id = std::process::id();
+        let dir = std::env::temp_dir().join(format!("mem-fuse-import-src-{}", id));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("capture.log"), b"first\nsecond\n").unwrap();
+        let data_dir = std::env::temp_dir().join(format!("mem-fuse-import-data-{}", id));
+        let data_dir = data_dir.to_str().unwrap();
+        let _ = fs::remove_dir_all(data_dir);
+
+        new_fs_with(data_dir, None).import(&dir).unwrap();
+        let fs = new_fs_with(data_dir, None);
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(data_dir).unwrap();
+
+        let attr = fs.lookup_name(b"capture.log").unwrap();
+        assert_eq!(fs.files[&attr.ino].lines, vec!["first", "second"]);
+        assert_eq!(attr.size, 13);
+    }
+
+    #[test]
+    fn write_missing_inode() {
+        let mut fs = new_fs();
+        assert_eq!(fs.write_inode(4711, b"line"), Err(libc::EBADF));
+    }
+
+    #[test]
+    fn write_directory() {
+        let mut fs = new_fs();
+        assert_eq!(fs.write_inode(FUSE_ROOT_ID, b"line"), Err(libc::EISDIR));
+    }
+
+    #[test]
+    fn write_beyond_max_file_size() {
+        let mut fs = new_fs_with("", Some(10));
+        let attr = fs.allocate_inode(b"file", 0o644, 1000, 100).unwrap();
+        fs.write_inode(attr.ino, b"12345\n").unwrap();
+        assert_eq!(fs.write_inode(attr.ino, b"67890\n"), Err(libc::EFBIG));
+        fs.write_inode(attr.ino, b"6789").unwrap();
+        assert_eq!(fs.files[&attr.ino].attr.size, 10);
+    }
+
+    #[test]
+    fn write_invalid_utf8() {
+        let mut fs = new_fs();
+        let attr = fs.allocate_inode(b"file", 0o644, 1000, 100).unwrap();
+        assert_eq!(fs.write_inode(attr.ino, b"\xff\xfe"), Err(libc::EINVAL));
+    }
+
+    #[test]
+    fn save_and_load_metadata() {
+        let data_dir = std::env::temp_dir().join(format!("mem-fuse-test-{}", std::process::id()));
+        let data_dir = data_dir.to_str().unwrap().to_string();
+
+        let mut fs = new_fs_with(&data_dir, None);
+        let first = fs.allocate_inode(b"first", 0o644, 1000, 100).unwrap();
+        fs.write_inode(first.ino, b"line").unwrap();
+        let second = fs.allocate_inode(b"\xff", 0o600, 1001, 101).unwrap();
+
+        let fs = new_fs_with(&data_dir, None);
+        std::fs::remove_dir_all(&data_dir).unwrap();
+        assert_eq!(fs.last_inode, second.ino);
+        let attr = fs.lookup_name(b"first").unwrap();
+        assert_eq!(attr.ino, first.ino);
+        assert_eq!(attr.size, 4);
+        assert_eq!(fs.files[&attr.ino].lines, vec!["line"]);
+        assert_eq!(attr.perm, 0o644);
+        assert_eq!((attr.uid, attr.gid), (1000, 100));
+        let attr = fs.lookup_name(b"\xff").unwrap();
+        assert_eq!(attr.ino, second.ino);
+        assert_eq!((attr.uid, attr.gid), (1001, 101));
+    }
+
+    #[test]
+    fn allocate_inode_save_fails() {
+        let data_dir =
+            std::env::temp_dir().join(format!("mem-fuse-unsaved-{}", std::process::id()));
+        let mut fs = new_fs_with(data_dir.to_str().unwrap(), None);
+        let _ = fs::remove_dir_all(&data_dir);
+        assert_eq!(fs.allocate_inode(b"file", 0o644, 1000, 100), Err(libc::EIO));
+        assert_eq!(fs.lookup_name(b"file"), Err(ENOENT));
+        assert!(fs.files.is_empty());
+    }
+
+    #[test]
+    fn metadata_versions() {
+        let attr = new_fs().allocate_inode(b"file", 0o644, 1000, 100).unwrap();
+        let mut value = metadata_json(b"file", &attr);
+        assert!(parse_metadata(&value, 512).is_ok());
+        value["version"] = json!(METADATA_VERSION + 1);
+        assert!(parse_metadata(&value, 512).is_err());
+    }
 }
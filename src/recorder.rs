@@ -0,0 +1,432 @@
+//! Recording of the operations sent to a file system.
+//!
+//! Each operation is written as a line with a flat JSON object, with
+//! the name of the operation in the `op` field and the arguments in
+//! the remaining fields. Names and data are stored as hex strings,
+//! since they do not have to be valid UTF-8, so the log can be read
+//! back with `parse_entry`.
+
+use fuser::{
+    Filesystem, KernelConfig, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
+    ReplyEntry, ReplyIoctl, ReplyOpen, ReplyWrite, ReplyXattr, Request, TimeOrNow,
+};
+use libc::c_int;
+use log::error;
+use serde_json::{Map, Value};
+use std::ffi::{OsStr, OsString};
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::time::SystemTime;
+
+/// A single operation being written to the log.
+pub struct Entry(Map<String, Value>);
+
+impl Entry {
+    pub fn new(op: &str) -> Entry {
+        let mut fields = Map::new();
+        fields.insert("op".to_string(), op.into());
+        Entry(fields)
+    }
+
+    pub fn num(mut self, key: &str, value: impl Into<Value>) -> Entry {
+        self.0.insert(key.to_string(), value.into());
+        self
+    }
+
+    /// Add a number if it is present.
+    pub fn opt(self, key: &str, value: Option<impl Into<Value>>) -> Entry {
+        match value {
+            Some(value) => self.num(key, value),
+            None => self,
+        }
+    }
+
+    pub fn str(self, key: &str, value: &OsStr) -> Entry {
+        self.bytes(key, value.as_bytes())
+    }
+
+    pub fn bytes(mut self, key: &str, value: &[u8]) -> Entry {
+        let mut hex = String::with_capacity(2 * value.len());
+        for byte in value {
+            write!(hex, "{:02x}", byte).unwrap();
+        }
+        self.0.insert(key.to_string(), hex.into());
+        self
+    }
+
+    pub fn finish(self) -> String {
+        Value::Object(self.0).to_string()
+    }
+}
+
+/// A single operation read from the log, with accessors matching the
+/// ones used to build the `Entry`.
+pub struct Fields(Map<String, Value>);
+
+impl Fields {
+    fn get(&self, key: &str) -> Result<&Value, String> {
+        self.0
+            .get(key)
+            .ok_or_else(|| format!("missing field {:?}", key))
+    }
+
+    /// Name of the operation.
+    pub fn op(&self) -> Result<&str, String> {
+        self.get("op")?
+            .as_str()
+            .ok_or_else(|| "field \"op\" is not a string".to_string())
+    }
+
+    pub fn num<T: TryFrom<i64>>(&self, key: &str) -> Result<T, String> {
+        self.get(key)?
+            .as_i64()
+            .and_then(|value| T::try_from(value).ok())
+            .ok_or_else(|| format!("field {:?} is not a valid number", key))
+    }
+
+    /// Number that is not present in logs recorded before it was
+    /// added.
+    pub fn opt<T: TryFrom<i64>>(&self, key: &str) -> Result<Option<T>, String> {
+        match self.0.get(key) {
+            Some(_) => self.num(key).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn str(&self, key: &str) -> Result<OsString, String> {
+        self.bytes(key).map(OsString::from_vec)
+    }
+
+    pub fn bytes(&self, key: &str) -> Result<Vec<u8>, String> {
+        let value = self
+            .get(key)?
+            .as_str()
+            .ok_or_else(|| format!("field {:?} is not a string", key))?;
+        (0..value.len())
+            .step_by(2)
+            .map(|i| {
+                value
+                    .get(i..i + 2)
+                    .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                    .ok_or_else(|| format!("bad hex data in field {:?}: {}", key, value))
+            })
+            .collect()
+    }
+}
+
+/// Parse a line written by the recorder.
+pub fn parse_entry(line: &str) -> Result<Fields, String> {
+    match serde_json::from_str(line).map_err(|err| err.to_string())? {
+        Value::Object(fields) => Ok(Fields(fields)),
+        _ => Err(format!("expected an object: {}", line)),
+    }
+}
+
+/**
+ * File system that logs each operation before passing it on to the
+ * file system it wraps.
+ *
+ * Only the operations implemented by the file systems in this
+ * repository are passed on. Any other operation gets the default
+ * reply from fuser, which is what the wrapped file system would reply
+ * as well.
+ */
+pub struct Recorder<FS: Filesystem> {
+    inner: FS,
+    log: BufWriter<File>,
+}
+
+impl<FS: Filesystem> Recorder<FS> {
+    pub fn new(inner: FS, log: File) -> Recorder<FS> {
+        Recorder {
+            inner,
+            log: BufWriter::new(log),
+        }
+    }
+
+    fn record(&mut self, entry: Entry) {
+        let result = writeln!(self.log, "{}", entry.finish()).and_then(|_| self.log.flush());
+        if let Err(err) = result {
+            error!("failed to record operation: {}", err);
+        }
+    }
+}
+
+impl<FS: Filesystem> Filesystem for Recorder<FS> {
+    fn init(&mut self, req: &Request, config: &mut KernelConfig) -> Result<(), c_int> {
+        self.record(Entry::new("init"));
+        self.inner.init(req, config)
+    }
+
+    fn destroy(&mut self) {
+        self.record(Entry::new("destroy"));
+        self.inner.destroy()
+    }
+
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        self.record(Entry::new("lookup").num("parent", parent).str("name", name));
+        self.inner.lookup(req, parent, name, reply)
+    }
+
+    fn forget(&mut self, req: &Request, ino: u64, nlookup: u64) {
+        self.record(Entry::new("forget").num("ino", ino).num("nlookup", nlookup));
+        self.inner.forget(req, ino, nlookup)
+    }
+
+    fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
+        self.record(Entry::new("getattr").num("ino", ino));
+        self.inner.getattr(req, ino, reply)
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        crtime: Option<SystemTime>,
+        chgtime: Option<SystemTime>,
+        bkuptime: Option<SystemTime>,
+        flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        self.record(
+            Entry::new("setattr")
+                .num("ino", ino)
+                .opt("mode", mode)
+                .opt("uid", uid)
+                .opt("gid", gid)
+                .opt("size", size),
+        );
+        self.inner.setattr(
+            req, ino, mode, uid, gid, size, atime, mtime, ctime, fh, crtime, chgtime, bkuptime,
+            flags, reply,
+        )
+    }
+
+    fn getxattr(&mut self, req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        self.record(
+            Entry::new("getxattr")
+                .num("ino", ino)
+                .str("name", name)
+                .num("size", size),
+        );
+        self.inner.getxattr(req, ino, name, size, reply)
+    }
+
+    fn listxattr(&mut self, req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        self.record(Entry::new("listxattr").num("ino", ino).num("size", size));
+        self.inner.listxattr(req, ino, size, reply)
+    }
+
+    fn readlink(&mut self, req: &Request, ino: u64, reply: ReplyData) {
+        self.record(Entry::new("readlink").num("ino", ino));
+        self.inner.readlink(req, ino, reply)
+    }
+
+    fn open(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        self.record(Entry::new("open").num("ino", ino).num("flags", flags));
+        self.inner.open(req, ino, flags, reply)
+    }
+
+    fn release(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        flags: i32,
+        lock_owner: Option<u64>,
+        flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.record(Entry::new("release").num("ino", ino).num("fh", fh));
+        self.inner
+            .release(req, ino, fh, flags, lock_owner, flush, reply)
+    }
+
+    fn flush(&mut self, req: &Request, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        self.record(Entry::new("flush").num("ino", ino).num("fh", fh));
+        self.inner.flush(req, ino, fh, lock_owner, reply)
+    }
+
+    fn read(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        flags: i32,
+        lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        self.record(
+            Entry::new("read")
+                .num("ino", ino)
+                .num("fh", fh)
+                .num("offset", offset)
+                .num("size", size),
+        );
+        self.inner
+            .read(req, ino, fh, offset, size, flags, lock_owner, reply)
+    }
+
+    fn opendir(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        self.record(Entry::new("opendir").num("ino", ino));
+        self.inner.opendir(req, ino, flags, reply)
+    }
+
+    fn releasedir(&mut self, req: &Request, ino: u64, fh: u64, flags: i32, reply: ReplyEmpty) {
+        self.record(Entry::new("releasedir").num("ino", ino).num("fh", fh));
+        self.inner.releasedir(req, ino, fh, flags, reply)
+    }
+
+    fn readdir(&mut self, req: &Request, ino: u64, fh: u64, offset: i64, reply: ReplyDirectory) {
+        self.record(
+            Entry::new("readdir")
+                .num("ino", ino)
+                .num("fh", fh)
+                .num("offset", offset),
+        );
+        self.inner.readdir(req, ino, fh, offset, reply)
+    }
+
+    fn create(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        self.record(
+            Entry::new("create")
+                .num("parent", parent)
+                .str("name", name)
+                .num("mode", mode)
+                .num("uid", req.uid())
+                .num("gid", req.gid())
+                .num("flags", flags),
+        );
+        self.inner
+            .create(req, parent, name, mode, umask, flags, reply)
+    }
+
+    fn write(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        write_flags: u32,
+        flags: i32,
+        lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        self.record(
+            Entry::new("write")
+                .num("uid", req.uid())
+                .num("pid", req.pid())
+                .num("ino", ino)
+                .num("fh", fh)
+                .num("offset", offset)
+                .bytes("data", data),
+        );
+        self.inner.write(
+            req,
+            ino,
+            fh,
+            offset,
+            data,
+            write_flags,
+            flags,
+            lock_owner,
+            reply,
+        )
+    }
+
+    fn ioctl(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+        out_size: u32,
+        reply: ReplyIoctl,
+    ) {
+        self.record(Entry::new("ioctl").num("ino", ino).num("cmd", cmd));
+        self.inner
+            .ioctl(req, ino, fh, flags, cmd, in_data, out_size, reply)
+    }
+
+    fn copy_file_range(
+        &mut self,
+        req: &Request,
+        ino_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+        reply: ReplyWrite,
+    ) {
+        self.record(
+            Entry::new("copy_file_range")
+                .num("ino_in", ino_in)
+                .num("offset_in", offset_in)
+                .num("ino_out", ino_out)
+                .num("offset_out", offset_out)
+                .num("len", len),
+        );
+        self.inner.copy_file_range(
+            req, ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags, reply,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_round_trip() {
+        let line = Entry::new("create")
+            .num("parent", 1)
+            .num("offset", -1i64)
+            .str("name", OsStr::from_bytes(b"a \"file\"\n\xff"))
+            .bytes("data", b"line\n\xff")
+            .finish();
+        let fields = parse_entry(&line).unwrap();
+        assert_eq!(fields.op().unwrap(), "create");
+        assert_eq!(fields.num::<u64>("parent").unwrap(), 1);
+        assert_eq!(fields.num::<i64>("offset").unwrap(), -1);
+        assert_eq!(fields.opt::<u32>("uid").unwrap(), None);
+        assert_eq!(fields.str("name").unwrap().as_bytes(), b"a \"file\"\n\xff");
+        assert_eq!(fields.bytes("data").unwrap(), b"line\n\xff");
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(parse_entry("[1]").is_err());
+        assert!(parse_entry("{\"op\":").is_err());
+        let fields = parse_entry(r#"{"op":"write","ino":"10","data":"6"}"#).unwrap();
+        assert!(fields.num::<u64>("ino").is_err());
+        assert!(fields.num::<u64>("offset").is_err());
+        assert!(fields.bytes("data").is_err());
+    }
+}
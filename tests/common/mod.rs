@@ -0,0 +1,93 @@
+//! Support for mounting the file systems in tests.
+//!
+//! The file system binaries are started as separate processes and
+//! mounted on a fresh directory under the system temporary
+//! directory. The file system is unmounted and the process killed
+//! when the mount is dropped.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MOUNT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Path under the system temporary directory that no other test in
+/// any test process uses, since tests run in parallel.
+pub fn temp_path(name: &str) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("{}-{}-{}", name, std::process::id(), count))
+}
+
+pub struct Mount {
+    path: PathBuf,
+    child: Child,
+}
+
+impl Mount {
+    /// Start `program` with `args` and mount it on a new directory.
+    ///
+    /// The mountpoint is passed to the program as the first
+    /// positional argument, before `args`.
+    pub fn new(program: &str, name: &str, args: &[&str]) -> Mount {
+        let path = temp_path(name);
+        fs::create_dir_all(&path).unwrap();
+        let child = Command::new(program).arg(&path).args(args).spawn().unwrap();
+        let mut mount = Mount { path, child };
+        mount.wait_for_mount();
+        mount
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Unmount the file system without waiting for the process to
+    /// exit.
+    pub fn unmount(&self) {
+        let _ = Command::new("fusermount3")
+            .arg("-u")
+            .arg(&self.path)
+            .status();
+    }
+
+    pub fn is_mounted(&self) -> bool {
+        self.fs_type().is_some()
+    }
+
+    /// File system type of the mount as shown in `/proc/mounts`, or
+    /// `None` if it is not mounted.
+    pub fn fs_type(&self) -> Option<String> {
+        let mounts = fs::read_to_string("/proc/mounts").unwrap();
+        let path = self.path.to_str().unwrap();
+        mounts.lines().find_map(|line| {
+            let fields: Vec<_> = line.split(' ').collect();
+            (fields.get(1) == Some(&path)).then(|| fields[2].to_string())
+        })
+    }
+
+    fn wait_for_mount(&mut self) {
+        let start = Instant::now();
+        while !self.is_mounted() {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                panic!("file system exited before mounting: {}", status);
+            }
+            if start.elapsed() > MOUNT_TIMEOUT {
+                panic!("timeout waiting for {:?} to be mounted", self.path);
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+impl Drop for Mount {
+    fn drop(&mut self) {
+        self.unmount();
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir(&self.path);
+    }
+}
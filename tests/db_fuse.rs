@@ -0,0 +1,521 @@
+//! Tests for db-fuse. These need a database to connect to, which is
+//! given by the `TEST_DATABASE_URL` environment variable. If it is
+//! not set, the tests do nothing.
+
+mod common;
+
+use common::Mount;
+use postgres::{Client, NoTls};
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Deref;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::process::Command;
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Mount of db-fuse holding the lock for using the database until
+/// after it is unmounted.
+struct DbMount {
+    mount: Mount,
+    _guard: MutexGuard<'static, ()>,
+}
+
+impl Deref for DbMount {
+    type Target = Mount;
+
+    fn deref(&self) -> &Mount {
+        &self.mount
+    }
+}
+
+fn mount() -> Option<DbMount> {
+    mount_with(&[])
+}
+
+fn mount_with(options: &[&str]) -> Option<DbMount> {
+    let params = std::env::var("TEST_DATABASE_URL").ok()?;
+    let guard = DATABASE.lock().unwrap_or_else(|err| err.into_inner());
+    drop_schema(&params);
+    let mut args = options.to_vec();
+    args.push(&params);
+    Some(DbMount {
+        mount: Mount::new(env!("CARGO_BIN_EXE_db-fuse"), "db-fuse", &args),
+        _guard: guard,
+    })
+}
+
+/// The tables are kept after unmounting, so start each test from an
+/// empty database.
+fn drop_schema(params: &str) {
+    let status = Command::new(env!("CARGO_BIN_EXE_db-fuse"))
+        .args(["--drop-schema", "--force", params])
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+/// Tests share one database and run in parallel, so each holds this
+/// lock while it uses the database.
+static DATABASE: Mutex<()> = Mutex::new(());
+
+/// Replay `log` with `options` against an empty database without
+/// mounting, returning a client connected to the database and the
+/// lock for using it.
+fn replay(options: &[&str], log: &str) -> Option<(MutexGuard<'static, ()>, Client)> {
+    let params = std::env::var("TEST_DATABASE_URL").ok()?;
+    let guard = DATABASE.lock().unwrap_or_else(|err| err.into_inner());
+    drop_schema(&params);
+    let path = common::temp_path("db-fuse-log");
+    fs::write(&path, log).unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_db-fuse"))
+        .args(options)
+        .arg("--replay")
+        .arg(&path)
+        .arg(std::env::temp_dir())
+        .arg(&params)
+        .status()
+        .unwrap();
+    fs::remove_file(&path).unwrap();
+    assert!(status.success());
+    Some((guard, Client::connect(&params, NoTls).unwrap()))
+}
+
+/// Seed an empty database with `spec` twice, returning a client
+/// connected to the database and the lock for using it.
+fn seed_twice(spec: &str) -> Option<(MutexGuard<'static, ()>, Client)> {
+    let params = std::env::var("TEST_DATABASE_URL").ok()?;
+    let guard = DATABASE.lock().unwrap_or_else(|err| err.into_inner());
+    drop_schema(&params);
+    for _ in 0..2 {
+        let status = Command::new(env!("CARGO_BIN_EXE_db-fuse"))
+            .args(["--seed", spec, &params])
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+    Some((guard, Client::connect(&params, NoTls).unwrap()))
+}
+
+fn content_rows(txn: &mut postgres::Transaction) -> i64 {
+    txn.query_one("SELECT count(*) FROM content", &[])
+        .unwrap()
+        .get(0)
+}
+
+#[test]
+#[ignore = "requires FUSE"]
+fn create_and_write() {
+    let mount = match mount() {
+        Some(mount) => mount,
+        None => return,
+    };
+    let path = mount.path().join("capture.log");
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .unwrap();
+    file.write_all(b"first line\nsecond line\n").unwrap();
+    drop(file);
+
+    let metadata = fs::metadata(&path).unwrap();
+    assert!(metadata.is_file());
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+
+    let names: Vec<_> = fs::read_dir(mount.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(names, vec!["capture.log"]);
+
+    let status = fs::read_to_string(mount.path().join(".status")).unwrap();
+    assert!(status.contains("rows_committed: 2\n"), "{}", status);
+}
+
+#[test]
+#[ignore = "requires FUSE"]
+fn drain_on_unmount() {
+    let mount = match mount_with(&["--async-writes", "--commit-interval", "3600000"]) {
+        Some(mount) => mount,
+        None => return,
+    };
+    let mut file = fs::File::create(mount.path().join("capture.log")).unwrap();
+    file.write_all(b"first line\nsecond line\n").unwrap();
+    drop(file);
+
+    let params = std::env::var("TEST_DATABASE_URL").unwrap();
+    let mut client = Client::connect(&params, NoTls).unwrap();
+    let mut txn = client.transaction().unwrap();
+    assert_eq!(content_rows(&mut txn), 0);
+
+    mount.unmount();
+    let start = Instant::now();
+    while content_rows(&mut txn) < 2 {
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "writes not committed on unmount"
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+    txn.rollback().unwrap();
+}
+
+#[test]
+#[ignore = "requires FUSE"]
+fn commit_within_interval() {
+    let interval = Duration::from_millis(200);
+    let millis = interval.as_millis().to_string();
+    let mount = match mount_with(&["--async-writes", "--commit-interval-ms", &millis]) {
+        Some(mount) => mount,
+        None => return,
+    };
+    let params = std::env::var("TEST_DATABASE_URL").unwrap();
+    let mut client = Client::connect(&params, NoTls).unwrap();
+
+    let start = Instant::now();
+    let mut file = fs::File::create(mount.path().join("capture.log")).unwrap();
+    file.write_all(b"first line\nsecond line\n").unwrap();
+    drop(file);
+
+    // Allow for some scheduling delay on top of the interval.
+    let mut txn = client.transaction().unwrap();
+    while content_rows(&mut txn) < 2 {
+        assert!(
+            start.elapsed() < interval + Duration::from_secs(1),
+            "writes not committed within the commit interval"
+        );
+        thread::sleep(Duration::from_millis(20));
+    }
+    txn.rollback().unwrap();
+
+    let status = fs::read_to_string(mount.path().join(".status")).unwrap();
+    assert!(status.contains("commits: 1\n"), "{}", status);
+}
+
+#[test]
+#[ignore = "requires FUSE"]
+fn clear_special_mode_bits() {
+    let mount = match mount() {
+        Some(mount) => mount,
+        None => return,
+    };
+    let path = mount.path().join("capture.log");
+    fs::write(&path, b"first line\n").unwrap();
+
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o7755)).unwrap();
+    let metadata = fs::metadata(&path).unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o7777, 0o755);
+}
+
+#[test]
+#[ignore = "requires FUSE"]
+fn subtype() {
+    let mount = match mount_with(&["--subtype", "capture"]) {
+        Some(mount) => mount,
+        None => return,
+    };
+    assert_eq!(mount.fs_type().as_deref(), Some("fuse.capture"));
+}
+
+#[test]
+#[ignore = "requires FUSE"]
+fn write_without_delimiter() {
+    let mount = match mount() {
+        Some(mount) => mount,
+        None => return,
+    };
+    let params = std::env::var("TEST_DATABASE_URL").unwrap();
+    let mut client = Client::connect(&params, NoTls).unwrap();
+
+    let mut file = fs::File::create(mount.path().join("capture.log")).unwrap();
+    file.write_all(b"first ").unwrap();
+    file.write_all(b"line\n").unwrap();
+    drop(file);
+
+    let lines: Vec<String> = client
+        .query("SELECT line FROM content ORDER BY seq", &[])
+        .unwrap()
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    assert_eq!(lines, ["first ", "line"]);
+}
+
+#[test]
+fn create_with_truncate() {
+    let (_guard, mut client) = match replay(
+        &[],
+        concat!(
+            r#"{"op":"create","name":"66","mode":33188,"uid":0,"gid":0,"flags":0}"#,
+            "\n",
+            r#"{"op":"write","ino":10,"offset":0,"data":"68656c6c6f0a"}"#,
+            "\n",
+            r#"{"op":"create","name":"66","mode":33188,"uid":0,"gid":0,"flags":512}"#,
+            "\n",
+        ),
+    ) {
+        Some(replayed) => replayed,
+        None => return,
+    };
+    let size: i64 = client
+        .query_one("SELECT size FROM inodes WHERE name = 'f'", &[])
+        .unwrap()
+        .get(0);
+    assert_eq!(size, 0);
+    let mut txn = client.transaction().unwrap();
+    assert_eq!(content_rows(&mut txn), 0);
+}
+
+#[test]
+#[ignore = "requires FUSE"]
+fn truncate_existing_file() {
+    let mount = match mount() {
+        Some(mount) => mount,
+        None => return,
+    };
+    let params = std::env::var("TEST_DATABASE_URL").unwrap();
+    let mut client = Client::connect(&params, NoTls).unwrap();
+    let path = mount.path().join("capture.log");
+    fs::write(&path, b"first line\nsecond line\n").unwrap();
+
+    let mut file = fs::File::create(&path).unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+    file.write_all(b"third line\n").unwrap();
+    drop(file);
+    assert_eq!(fs::metadata(&path).unwrap().len(), 11);
+
+    let lines: Vec<String> = client
+        .query("SELECT line FROM content ORDER BY seq", &[])
+        .unwrap()
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    assert_eq!(lines, ["third line"]);
+}
+
+#[test]
+#[ignore = "requires FUSE"]
+fn write_invalid_utf8() {
+    let mount = match mount() {
+        Some(mount) => mount,
+        None => return,
+    };
+    let params = std::env::var("TEST_DATABASE_URL").unwrap();
+    let mut client = Client::connect(&params, NoTls).unwrap();
+
+    let mut file = fs::File::create(mount.path().join("capture.log")).unwrap();
+    let err = file.write_all(b"\xff\xfe\n").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+    drop(file);
+
+    let mut txn = client.transaction().unwrap();
+    assert_eq!(content_rows(&mut txn), 0);
+}
+
+#[test]
+#[ignore = "requires FUSE"]
+fn dedup() {
+    let mount = match mount_with(&["--dedup", "--create-views"]) {
+        Some(mount) => mount,
+        None => return,
+    };
+    let params = std::env::var("TEST_DATABASE_URL").unwrap();
+    let mut client = Client::connect(&params, NoTls).unwrap();
+
+    fs::write(mount.path().join("first.log"), b"same\nother\nsame\n").unwrap();
+    fs::write(mount.path().join("second.log"), b"same\n").unwrap();
+
+    let row = client
+        .query_one(
+            "SELECT (SELECT count(*) FROM dictionary), \
+                    (SELECT contents FROM files WHERE name = 'first.log')",
+            &[],
+        )
+        .unwrap();
+    assert_eq!(row.get::<_, i64>(0), 2);
+    assert_eq!(row.get::<_, &str>(1), "same\nother\nsame");
+}
+
+#[test]
+#[ignore = "requires FUSE"]
+fn record_lines_stats() {
+    let mount = match mount_with(&["--record-lines", "2"]) {
+        Some(mount) => mount,
+        None => return,
+    };
+    fs::write(
+        mount.path().join("capture.log"),
+        b"first\nsecond\n\nthird\n",
+    )
+    .unwrap();
+
+    let stats = fs::read_to_string(mount.path().join(".stats/capture.log")).unwrap();
+    assert!(stats.starts_with("lines: 3\n"), "{}", stats);
+}
+
+#[test]
+fn create_reserved_name() {
+    let (_guard, mut client) = match replay(
+        &[],
+        concat!(
+            r#"{"op":"create","name":"6c6174657374","mode":33188,"uid":0,"gid":0,"flags":0}"#,
+            "\n",
+            r#"{"op":"create","name":"2e737461747573","mode":33188,"uid":0,"gid":0,"flags":0}"#,
+            "\n",
+            r#"{"op":"create","name":"66","mode":33188,"uid":0,"gid":0,"flags":0}"#,
+            "\n",
+        ),
+    ) {
+        Some(replayed) => replayed,
+        None => return,
+    };
+    let names: Vec<String> = client
+        .query(
+            "SELECT name::text FROM inodes WHERE ino <> 1 ORDER BY ino",
+            &[],
+        )
+        .unwrap()
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    assert_eq!(names, ["f"]);
+}
+
+#[test]
+fn seed_existing_files() {
+    let (_guard, mut client) = match seed_twice("2:3") {
+        Some(seeded) => seeded,
+        None => return,
+    };
+    let row = client
+        .query_one(
+            "SELECT (SELECT count(*) FROM inodes WHERE name LIKE 'seed-%'), \
+                    (SELECT count(*) FROM content)",
+            &[],
+        )
+        .unwrap();
+    assert_eq!(row.get::<_, i64>(0), 2);
+    assert_eq!(row.get::<_, i64>(1), 6);
+}
+
+#[test]
+fn dedup_same_line() {
+    let (_guard, mut client) = match replay(
+        &["--dedup"],
+        concat!(
+            r#"{"op":"create","name":"66","mode":33188,"uid":0,"gid":0,"flags":0}"#,
+            "\n",
+            r#"{"op":"write","ino":10,"offset":0,"data":"73616d650a"}"#,
+            "\n",
+            r#"{"op":"write","ino":10,"offset":5,"data":"73616d650a"}"#,
+            "\n",
+        ),
+    ) {
+        Some(replayed) => replayed,
+        None => return,
+    };
+    let row = client
+        .query_one(
+            "SELECT (SELECT count(*) FROM dictionary), (SELECT count(*) FROM content)",
+            &[],
+        )
+        .unwrap();
+    assert_eq!(row.get::<_, i64>(0), 1);
+    assert_eq!(row.get::<_, i64>(1), 2);
+}
+
+#[test]
+fn files_view_delimiter() {
+    let (_guard, mut client) = match replay(
+        &["--create-views", "--delimiter", ";"],
+        concat!(
+            r#"{"op":"create","name":"66","mode":33188,"uid":0,"gid":0,"flags":0}"#,
+            "\n",
+            r#"{"op":"write","ino":10,"offset":0,"data":"66697273743b7365636f6e643b"}"#,
+            "\n",
+        ),
+    ) {
+        Some(replayed) => replayed,
+        None => return,
+    };
+    let row = client
+        .query_one("SELECT contents FROM files WHERE name = 'f'", &[])
+        .unwrap();
+    assert_eq!(row.get::<_, &str>(0), "first;second");
+}
+
+#[test]
+#[ignore = "requires FUSE"]
+fn stream_mode() {
+    let mount = match mount_with(&["--stream-mode"]) {
+        Some(mount) => mount,
+        None => return,
+    };
+    let path = mount.path().join("stream");
+    for data in [&b"first line\n\n"[..], b"second ", b"line\n"] {
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(data).unwrap();
+    }
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        "first line\n\nsecond line\n"
+    );
+    let mut file = fs::File::open(&path).unwrap();
+    file.seek(SeekFrom::Start(14)).unwrap();
+    let mut rest = String::new();
+    file.read_to_string(&mut rest).unwrap();
+    assert_eq!(rest, "cond line\n");
+
+    let names: Vec<_> = fs::read_dir(mount.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(names, vec!["stream"]);
+
+    let err = fs::File::create(mount.path().join("other.log")).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+}
+
+#[test]
+#[ignore = "requires FUSE"]
+fn insert_on_close() {
+    let mount = match mount_with(&["--write-policy", "on-close"]) {
+        Some(mount) => mount,
+        None => return,
+    };
+    let params = std::env::var("TEST_DATABASE_URL").unwrap();
+    let mut client = Client::connect(&params, NoTls).unwrap();
+
+    let mut file = fs::File::create(mount.path().join("capture.log")).unwrap();
+    file.write_all(b"first line\n").unwrap();
+    file.write_all(b"second line\n").unwrap();
+
+    let mut txn = client.transaction().unwrap();
+    assert_eq!(content_rows(&mut txn), 0);
+    drop(file);
+    assert_eq!(content_rows(&mut txn), 2);
+    txn.rollback().unwrap();
+}
+
+#[test]
+#[ignore = "requires FUSE"]
+fn unmount_when_idle() {
+    let mount = match mount_with(&["--idle-timeout", "1"]) {
+        Some(mount) => mount,
+        None => return,
+    };
+    fs::write(mount.path().join("capture.log"), b"first line\n").unwrap();
+
+    let start = Instant::now();
+    while mount.is_mounted() {
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "not unmounted after being idle"
+        );
+        thread::sleep(Duration::from_millis(100));
+    }
+}
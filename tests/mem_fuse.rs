@@ -0,0 +1,138 @@
+mod common;
+
+use common::Mount;
+use std::ffi::CString;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::ops::Deref;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// Directory that is removed with its contents when dropped.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    /// New path for a directory, which is not created.
+    fn new(name: &str) -> TempDir {
+        TempDir(common::temp_path(name))
+    }
+
+    fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Mount of mem-fuse with its own data directory, which is removed
+/// after unmounting.
+struct MemMount {
+    mount: Mount,
+    _data_dir: TempDir,
+}
+
+impl Deref for MemMount {
+    type Target = Mount;
+
+    fn deref(&self) -> &Mount {
+        &self.mount
+    }
+}
+
+/// Mount mem-fuse with an empty data directory, so that no files
+/// saved by earlier runs or other tests are loaded.
+fn mount() -> MemMount {
+    let data_dir = TempDir::new("mem-fuse-data");
+    let mount = Mount::new(
+        env!("CARGO_BIN_EXE_mem-fuse"),
+        "mem-fuse",
+        &["unused", "--data-dir", data_dir.path().to_str().unwrap()],
+    );
+    MemMount {
+        mount,
+        _data_dir: data_dir,
+    }
+}
+
+#[test]
+#[ignore = "requires FUSE"]
+fn create_and_write() {
+    let mount = mount();
+    let path = mount.path().join("capture.log");
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .unwrap();
+    file.write_all(b"first line\nsecond line\n").unwrap();
+    drop(file);
+
+    let metadata = fs::metadata(&path).unwrap();
+    assert!(metadata.is_file());
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+
+    let names: Vec<_> = fs::read_dir(mount.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(names, vec!["capture.log"]);
+}
+
+#[test]
+#[ignore = "requires FUSE"]
+fn subtype() {
+    let mount = mount();
+    assert_eq!(mount.fs_type().as_deref(), Some("fuse.memfuse"));
+}
+
+#[test]
+#[ignore = "requires FUSE"]
+fn missing_file() {
+    let mount = mount();
+    let path = mount.path().join("missing.log");
+    let err = fs::metadata(path).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+}
+
+#[test]
+#[ignore = "requires FUSE"]
+fn truncate_existing_file() {
+    let mount = mount();
+    let path = mount.path().join("capture.log");
+    fs::write(&path, b"first line\nsecond line\n").unwrap();
+
+    let mut file = fs::File::create(&path).unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+    file.write_all(b"third line\n").unwrap();
+    drop(file);
+    assert_eq!(fs::metadata(&path).unwrap().len(), 11);
+}
+
+#[test]
+#[ignore = "requires FUSE"]
+fn line_count_xattr() {
+    let mount = mount();
+    let path = mount.path().join("capture.log");
+    fs::write(&path, b"first line\nsecond line").unwrap();
+
+    let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    let name = CString::new("user.line_count").unwrap();
+    let mut value = [0u8; 32];
+    let len = unsafe {
+        libc::getxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_mut_ptr() as *mut libc::c_void,
+            value.len(),
+        )
+    };
+    assert!(len >= 0, "{}", std::io::Error::last_os_error());
+    assert_eq!(&value[..len as usize], b"2");
+}